@@ -45,8 +45,11 @@ struct InnerBumpAlloc<Alloc: Allocator> {
     error_after: usize,
     min_alloc_size: usize,
     total_alloc_size: usize,
-    allocations: Vec<Slice>,
+    // Allocated chunks with the alignment they were allocated with
+    allocations: Vec<(Slice, usize)>,
     current_alloc: Slice,
+    // true if current_alloc is known to be zeroed
+    current_zeroed: bool,
 }
 
 pub struct BumpAlloc<Alloc: Allocator> {
@@ -59,10 +62,10 @@ impl<Alloc: Allocator> Drop for BumpAlloc<Alloc> {
             let mut this = self.inner.borrow_mut();
             let this = this.deref_mut();
 
-            for &x in this.allocations.iter() {
+            for &(x, align) in this.allocations.iter() {
                 this.base_alloc.deallocate(
                     NonNull::new(x.ptr as *mut u8).unwrap(),
-                    Layout::from_size_align(x.len, 1 << 12).unwrap(),
+                    Layout::from_size_align(x.len, align).unwrap(),
                 );
             }
         }
@@ -84,26 +87,43 @@ impl<Alloc: Allocator> BumpAlloc<Alloc> {
                     ptr: NonNull::dangling().as_ptr() as *mut u8 as usize,
                     len: 0,
                 },
+                current_zeroed: false,
             }),
         }
     }
-}
 
-// Safety: Allocations don't get invalidated when BumpAlloc is moved.
-unsafe impl<Alloc: Allocator> Allocator for BumpAlloc<Alloc> {
-    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let mut this = self.inner.borrow_mut();
-        let this = this.deref_mut();
+    /// Returns the total residency of the memory allocated from base_alloc,
+    /// see [crate::page_alloc::dynamic_page_alloc::residency].
+    #[cfg(target_os = "linux")]
+    pub fn residency(&self) -> std::io::Result<crate::page_alloc::dynamic_page_alloc::Residency> {
+        let this = self.inner.borrow();
+        let allocations = this
+            .allocations
+            .iter()
+            .map(|(x, _)| {
+                NonNull::slice_from_raw_parts(NonNull::new(x.ptr as *mut u8).unwrap(), x.len)
+            })
+            .collect::<Vec<_>>();
+        Ok(
+            crate::page_alloc::dynamic_page_alloc::residency(&allocations)?
+                .into_iter()
+                .sum(),
+        )
+    }
 
+    /// Allocates memory that is zeroed if zeroed is true.
+    ///
+    /// New chunks are allocated using allocate_zeroed of base_alloc for zeroed allocations,
+    /// so the memset can be skipped until the chunk is used up.
+    fn alloc(
+        this: &mut InnerBumpAlloc<Alloc>,
+        layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
         if this.error_after <= this.total_alloc_size {
             return Err(AllocError);
         }
 
-        // Alignment above 4KB is not allwed
-        if layout.align() > 1 << 12 {
-            return Err(AllocError);
-        }
-
         if layout.size() == 0 {
             return Ok(NonNull::slice_from_raw_parts(NonNull::dangling(), 0));
         }
@@ -116,29 +136,55 @@ unsafe impl<Alloc: Allocator> Allocator for BumpAlloc<Alloc> {
             this.current_alloc.ptr += align_offs + layout.size();
             this.current_alloc.len -= align_offs + layout.size();
 
+            if zeroed && !this.current_zeroed {
+                // Safety: memory was just allocated
+                unsafe { ptr.as_ptr().write_bytes(0, layout.size()) };
+            }
+
             return Ok(NonNull::slice_from_raw_parts(ptr, layout.size()));
         }
 
         let alloc_size = layout.size().max(this.min_alloc_size);
-        let alloc_layout = Layout::from_size_align(alloc_size, 1 << 12).unwrap();
+        let alloc_align = layout.align().max(1 << 12);
+        let alloc_layout = Layout::from_size_align(alloc_size, alloc_align).unwrap();
 
-        let new_alloc = this.base_alloc.allocate(alloc_layout)?;
+        let new_alloc = if zeroed {
+            this.base_alloc.allocate_zeroed(alloc_layout)?
+        } else {
+            this.base_alloc.allocate(alloc_layout)?
+        };
         let new_alloc = Slice {
             ptr: new_alloc.cast::<u8>().as_ptr() as usize,
             len: new_alloc.len(),
         };
 
-        this.allocations.push(new_alloc);
+        this.allocations.push((new_alloc, alloc_align));
 
         this.current_alloc = Slice {
             ptr: new_alloc.ptr + layout.size(),
             len: new_alloc.len - layout.size(),
         };
+        this.current_zeroed = zeroed;
 
         let ptr = NonNull::new(new_alloc.ptr as *mut u8).unwrap();
 
         Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
     }
+}
+
+// Safety: Allocations don't get invalidated when BumpAlloc is moved.
+unsafe impl<Alloc: Allocator> Allocator for BumpAlloc<Alloc> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut this = self.inner.borrow_mut();
+        let this = this.deref_mut();
+        Self::alloc(this, layout, false)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut this = self.inner.borrow_mut();
+        let this = this.deref_mut();
+        Self::alloc(this, layout, true)
+    }
 
     unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {}
 
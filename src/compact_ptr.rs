@@ -0,0 +1,73 @@
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+/// Pointer that is stored as a 32-bit offset from a base address.
+///
+/// This is meant to be used with a page allocator that places all pages inside a fixed address window,
+/// like [crate::page_alloc::region_page_alloc::RegionPageAlloc], so pointers into the window can be stored in half the space.
+/// Using a base address of 0 allows storing pointers to memory below 4GB.
+///
+/// The base address isn't stored, so it is up to the user to pass the same base address to [CompactPtr::new] and [CompactPtr::to_ptr].
+pub struct CompactPtr<T> {
+    offset: u32,
+    _marker: PhantomData<*const T>,
+}
+
+// Safety: CompactPtr is only an offset, it can be shared between threads whenever the pointee can.
+unsafe impl<T: Send> Send for CompactPtr<T> {}
+
+// Safety: see above
+unsafe impl<T: Sync> Sync for CompactPtr<T> {}
+
+impl<T> CompactPtr<T> {
+    /// Returns None if ptr is below base or if it is 4GB or more above it.
+    pub fn new(base: usize, ptr: NonNull<T>) -> Option<Self> {
+        let addr = ptr.as_ptr() as usize;
+        let offset = addr.checked_sub(base)?;
+        Some(Self::from_offset(u32::try_from(offset).ok()?))
+    }
+
+    pub fn from_offset(offset: u32) -> Self {
+        Self {
+            offset,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn offset(self) -> u32 {
+        self.offset
+    }
+
+    /// Converts back to a full pointer, base has to be the base address that was used to create this.
+    pub fn to_ptr(self, base: usize) -> NonNull<T> {
+        NonNull::new((base + self.offset as usize) as *mut T).unwrap()
+    }
+}
+
+impl<T> Clone for CompactPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for CompactPtr<T> {}
+
+impl<T> PartialEq for CompactPtr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.offset == other.offset
+    }
+}
+
+impl<T> Eq for CompactPtr<T> {}
+
+impl<T> std::hash::Hash for CompactPtr<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.offset.hash(state);
+    }
+}
+
+impl<T> std::fmt::Debug for CompactPtr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CompactPtr").field(&self.offset).finish()
+    }
+}
@@ -1,9 +1,8 @@
 #![feature(allocator_api)]
-#![feature(alloc_layout_extra)]
-#![feature(alloc_error_hook)]
 #![allow(clippy::comparison_chain)]
 
 pub mod bump_alloc;
+pub mod compact_ptr;
 pub mod local_alloc;
 pub mod page_alloc;
 mod util;
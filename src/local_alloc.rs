@@ -1,10 +1,11 @@
 use core::alloc::{AllocError, Allocator, Layout};
 use core::ptr::NonNull;
 use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::ops::DerefMut;
 
 use crate::page_alloc::PageAlloc;
-use crate::util::{align_offset, align_up};
+use crate::util::{align_down, align_offset, align_up};
 
 // Use this to avoid creating aliased pointers.
 // Not sure of all the details of unsafety of that but doing it to make "sure".
@@ -16,25 +17,181 @@ struct Slice {
     len: usize,
 }
 
-struct InnerLocalAlloc<'a> {
-    page_alloc: &'a dyn PageAlloc,
-    pages: Vec<Slice>,
-    free_list: Vec<Vec<Slice>>,
+impl Slice {
+    fn to_page(self) -> NonNull<[u8]> {
+        let ptr = NonNull::new(self.ptr as *mut u8).unwrap();
+        NonNull::slice_from_raw_parts(ptr, self.len)
+    }
+}
+
+const NUM_SIZE_CLASSES: usize = usize::BITS as usize;
+// Maximum number of ranges find checks in the classes that might fit, when no range is known to fit
+const MAX_SCANNED_RANGES: usize = 64;
+
+/// Free ranges of all pages, indexed by start address and by size class.
+///
+/// A free range with a length in [2^i, 2^(i+1)) is in size class i.
+/// Free ranges never cross page boundaries.
+struct FreeRanges {
+    // start address -> length
+    by_addr: BTreeMap<Ptr, usize>,
+    // start addresses of the free ranges in each size class
+    size_classes: Vec<BTreeSet<Ptr>>,
+    // bit i is set if size class i is not empty
+    non_empty_classes: usize,
+}
+
+impl FreeRanges {
+    fn new() -> Self {
+        Self {
+            by_addr: BTreeMap::new(),
+            size_classes: vec![BTreeSet::new(); NUM_SIZE_CLASSES],
+            non_empty_classes: 0,
+        }
+    }
+
+    fn size_class(len: usize) -> usize {
+        len.ilog2() as usize
+    }
+
+    fn insert(&mut self, range: Slice) {
+        assert_ne!(range.len, 0);
+        self.by_addr.insert(range.ptr, range.len);
+        let class = Self::size_class(range.len);
+        self.size_classes.get_mut(class).unwrap().insert(range.ptr);
+        self.non_empty_classes |= 1 << class;
+    }
+
+    fn remove(&mut self, range: Slice) {
+        self.by_addr.remove(&range.ptr);
+        let class = Self::size_class(range.len);
+        let ptrs = self.size_classes.get_mut(class).unwrap();
+        ptrs.remove(&range.ptr);
+        if ptrs.is_empty() {
+            self.non_empty_classes &= !(1 << class);
+        }
+    }
+
+    /// Returns the free range that starts at ptr.
+    fn starting_at(&self, ptr: Ptr) -> Option<Slice> {
+        let len = *self.by_addr.get(&ptr)?;
+        Some(Slice { ptr, len })
+    }
+
+    /// Returns the free range that ends at ptr.
+    fn ending_at(&self, ptr: Ptr) -> Option<Slice> {
+        let (&start, &len) = self.by_addr.range(..ptr).next_back()?;
+        (start + len == ptr).then_some(Slice { ptr: start, len })
+    }
+
+    fn iter(&self) -> impl Iterator<Item = Slice> + '_ {
+        self.by_addr.iter().map(|(&ptr, &len)| Slice { ptr, len })
+    }
+
+    /// Finds a free range that fits an allocation with the given layout.
+    ///
+    /// Size classes are tried from the class of the allocation size upwards, so free ranges of about the
+    /// size of the allocation are reused before bigger ones are split. Within a class the range with the
+    /// lowest address is used.
+    /// Ranges in the classes below the one of the worst case size only fit depending on their size and
+    /// alignment. Only the first range of each of those classes is checked at first, so the search takes
+    /// one lookup per size class. If that and the bigger classes don't have a fitting range, up to
+    /// MAX_SCANNED_RANGES more ranges of those classes are checked before giving up.
+    fn find(&self, layout: Layout) -> Option<Slice> {
+        let worst_case_size = layout.size() + layout.align() - 1;
+        let fitting_class = Self::size_class(worst_case_size) + 1;
+        let boundary_classes = Self::size_class(layout.size())..fitting_class.min(NUM_SIZE_CLASSES);
+        let fits =
+            |range: &Slice| range.len >= align_offset(range.ptr, layout.align()) + layout.size();
+
+        for class in boundary_classes.clone() {
+            if self.non_empty_classes & (1 << class) == 0 {
+                continue;
+            }
+            let ptr = *self.size_classes.get(class).unwrap().first().unwrap();
+            let range = self.starting_at(ptr).unwrap();
+            if fits(&range) {
+                return Some(range);
+            }
+        }
+
+        // Any range in a size class above the one of the worst case size fits the allocation,
+        // no matter how the range is aligned.
+        let fitting_classes =
+            self.non_empty_classes & usize::MAX.checked_shl(fitting_class as u32).unwrap_or(0);
+        if fitting_classes == 0 {
+            return boundary_classes
+                .flat_map(|class| self.size_classes.get(class).unwrap().iter().skip(1))
+                .take(MAX_SCANNED_RANGES)
+                .map(|&ptr| self.starting_at(ptr).unwrap())
+                .find(fits);
+        }
+        let class = fitting_classes.trailing_zeros() as usize;
+        let ptr = *self.size_classes.get(class).unwrap().first().unwrap();
+        self.starting_at(ptr)
+    }
+}
+
+struct Page {
+    ptr: Ptr,
+    len: usize,
+    // decommitted ranges inside the page, start address -> length. These are always inside free ranges,
+    // they don't overlap and adjacent ranges are merged.
+    decommitted: BTreeMap<Ptr, usize>,
+    // start of the part of the page that wasn't used since the page was allocated,
+    // it is known to be zero if the page allocator zeroes pages.
+    pristine: Ptr,
+}
+
+impl Page {
+    fn slice(&self) -> Slice {
+        Slice {
+            ptr: self.ptr,
+            len: self.len,
+        }
+    }
+}
+
+/// Returns the ranges of ranges that overlap or touch [start, end), ranges can't overlap each other.
+fn ranges_touching(ranges: &BTreeMap<Ptr, usize>, start: Ptr, end: Ptr) -> Vec<Slice> {
+    ranges
+        .range(..=end)
+        .rev()
+        .map(|(&ptr, &len)| Slice { ptr, len })
+        .take_while(|range| range.ptr + range.len >= start)
+        .collect()
+}
+
+/// Returns the page that contains ptr.
+fn page_containing(pages: &mut BTreeMap<Ptr, Page>, ptr: Ptr) -> Option<&mut Page> {
+    let (_, page) = pages.range_mut(..=ptr).next_back()?;
+    (ptr < page.ptr + page.len).then_some(page)
+}
+
+struct InnerLocalAlloc<P: PageAlloc> {
+    page_alloc: P,
+    // start address -> page
+    pages: BTreeMap<Ptr, Page>,
+    free_ranges: FreeRanges,
+    // start addresses of the pages that don't contain any allocations
+    empty_pages: BTreeSet<Ptr>,
+    decommit_threshold: usize,
     free_after: usize,
     error_after: usize,
     min_page_size: usize,
     total_page_size: usize,
-    ptr_to_size: Vec<(usize, usize)>,
+    // start address -> size of each live allocation
+    ptr_to_size: HashMap<Ptr, usize>,
 }
 
-pub struct LocalAlloc<'a> {
-    inner: RefCell<InnerLocalAlloc<'a>>,
+pub struct LocalAlloc<P: PageAlloc> {
+    inner: RefCell<InnerLocalAlloc<P>>,
 }
 
-impl Drop for LocalAlloc<'_> {
+impl<P: PageAlloc> Drop for LocalAlloc<P> {
     fn drop(&mut self) {
         let this = self.inner.borrow_mut();
-        for page in this.pages.iter() {
+        for page in this.pages.values() {
             // Safety: there are no references to the pages remaning at the time of drop.
             // So constructing a pointer to the page itself doesn't alias. Pages are never null.
             // And we only allocate pages using self.page_alloc
@@ -50,23 +207,34 @@ impl Drop for LocalAlloc<'_> {
     }
 }
 
-pub struct Config<'a> {
-    page_alloc: &'a dyn PageAlloc,
+pub struct Config<P: PageAlloc> {
+    page_alloc: P,
+    decommit_threshold: usize,
     free_after: usize,
     error_after: usize,
     min_page_size: usize,
 }
 
-impl<'a> Config<'a> {
-    pub fn new(page_alloc: &'a dyn PageAlloc) -> Self {
+impl<P: PageAlloc> Config<P> {
+    /// page_alloc can be a reference or a smart pointer like [std::sync::Arc] to share a page allocator
+    /// between allocators.
+    pub fn new(page_alloc: P) -> Self {
         Self {
             page_alloc,
+            decommit_threshold: usize::MAX,
             free_after: 1 << 29, // 512 MB
             error_after: usize::MAX,
             min_page_size: 1 << 27, // 128 MB
         }
     }
 
+    /// Free ranges inside pages that are at least this big get decommitted using the page allocator,
+    /// so the memory backing them can be returned to the OS without freeing the whole page.
+    pub fn decommit_threshold(&mut self, decommit_threshold: usize) -> &mut Self {
+        self.decommit_threshold = decommit_threshold;
+        self
+    }
+
     pub fn free_after(&mut self, free_after: usize) -> &mut Self {
         self.free_after = free_after;
         self
@@ -83,224 +251,459 @@ impl<'a> Config<'a> {
     }
 }
 
-impl<'a> LocalAlloc<'a> {
-    pub fn new(config: Config<'a>) -> Self {
+impl<P: PageAlloc> LocalAlloc<P> {
+    pub fn new(config: Config<P>) -> Self {
         Self {
             inner: RefCell::new(InnerLocalAlloc {
                 page_alloc: config.page_alloc,
+                decommit_threshold: config.decommit_threshold,
                 free_after: config.free_after,
                 error_after: config.error_after,
                 min_page_size: config.min_page_size,
-                free_list: Vec::new(),
-                pages: Vec::new(),
+                free_ranges: FreeRanges::new(),
+                empty_pages: BTreeSet::new(),
+                pages: BTreeMap::new(),
                 total_page_size: 0,
-                ptr_to_size: Vec::new(),
+                ptr_to_size: HashMap::new(),
             }),
         }
     }
 
+    /// Makes sure there are at least bytes of free memory in the pages of this allocator and faults in that much of it,
+    /// so the first allocations don't have to allocate pages or cause page faults.
+    ///
+    /// Pages might be freed again if the total size of pages goes above the free_after limit.
+    pub fn prefault(&self, bytes: usize) -> Result<(), AllocError> {
+        let mut this = self.inner.borrow_mut();
+        let this = this.deref_mut();
+
+        let free_size: usize = this.free_ranges.iter().map(|range| range.len).sum();
+        if free_size < bytes {
+            if this.error_after <= this.total_page_size {
+                return Err(AllocError);
+            }
+
+            let page = this
+                .page_alloc
+                .alloc_page((bytes - free_size).max(this.min_page_size))?;
+            let page = Slice {
+                ptr: page.cast::<u8>().as_ptr() as usize,
+                len: page.len(),
+            };
+            this.total_page_size += page.len;
+
+            this.pages.insert(
+                page.ptr,
+                Page {
+                    ptr: page.ptr,
+                    len: page.len,
+                    decommitted: BTreeMap::new(),
+                    pristine: Self::initial_pristine(&this.page_alloc, page),
+                },
+            );
+            this.free_ranges.insert(page);
+            this.empty_pages.insert(page.ptr);
+        }
+
+        let mut remaining = bytes;
+        for range in this.free_ranges.iter() {
+            if remaining == 0 {
+                return Ok(());
+            }
+
+            let start = align_up(range.ptr, 1 << 12);
+            let end = align_down(range.ptr + range.len, 1 << 12).min(start + remaining);
+            if start >= end {
+                continue;
+            }
+
+            let page = page_containing(&mut this.pages, start).unwrap();
+            Self::recommit_range(&this.page_alloc, &mut page.decommitted, start, end);
+            let range = Slice {
+                ptr: start,
+                len: end - start,
+            };
+            // Safety: range is a 4KB aligned part of a free range, it was just recommitted.
+            unsafe { this.page_alloc.prefault(range.to_page()) };
+            remaining -= range.len;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the total residency of the pages allocated by this allocator,
+    /// see [crate::page_alloc::dynamic_page_alloc::residency].
+    #[cfg(target_os = "linux")]
+    pub fn residency(&self) -> std::io::Result<crate::page_alloc::dynamic_page_alloc::Residency> {
+        let this = self.inner.borrow();
+        let pages = this
+            .pages
+            .values()
+            .map(|page| page.slice().to_page())
+            .collect::<Vec<_>>();
+        Ok(crate::page_alloc::dynamic_page_alloc::residency(&pages)?
+            .into_iter()
+            .sum())
+    }
+
+    /// Returns the allocation and the number of bytes at the start of it that are not known to be zero.
     fn try_alloc_in_existing_pages(
-        this: &mut InnerLocalAlloc,
+        this: &mut InnerLocalAlloc<P>,
         layout: Layout,
-    ) -> Option<NonNull<[u8]>> {
-        // Try to find a page that fits this allocation
-        for free_ranges in this.free_list.iter_mut() {
-            for free_range_idx in 0..free_ranges.len() {
-                let free_range = *free_ranges.get(free_range_idx).unwrap();
-                let alignment_offset = align_up(free_range.ptr, layout.align());
-                let needed_size = alignment_offset + layout.size();
-                if free_range.len >= needed_size {
-                    if alignment_offset > 0 {
-                        free_ranges.push(Slice {
-                            ptr: free_range.ptr,
-                            len: alignment_offset,
-                        });
-                    }
-                    if free_range.len > needed_size {
-                        free_ranges.push(Slice {
-                            ptr: free_range.ptr + needed_size,
-                            len: free_range.len - needed_size,
-                        })
-                    }
-                    free_ranges.swap_remove(free_range_idx);
-
-                    let ptr = NonNull::new((free_range.ptr + alignment_offset) as *mut u8).unwrap();
-                    return Some(NonNull::slice_from_raw_parts(ptr, layout.size()));
-                }
-            }
+    ) -> Option<(NonNull<[u8]>, usize)> {
+        let free_range = this.free_ranges.find(layout)?;
+        let alignment_offset = align_offset(free_range.ptr, layout.align());
+        let needed_size = alignment_offset + layout.size();
+
+        this.free_ranges.remove(free_range);
+        if alignment_offset > 0 {
+            this.free_ranges.insert(Slice {
+                ptr: free_range.ptr,
+                len: alignment_offset,
+            });
         }
+        if free_range.len > needed_size {
+            this.free_ranges.insert(Slice {
+                ptr: free_range.ptr + needed_size,
+                len: free_range.len - needed_size,
+            });
+        }
+
+        let start_addr = free_range.ptr + alignment_offset;
+        let page = page_containing(&mut this.pages, start_addr).unwrap();
+        this.empty_pages.remove(&page.ptr);
+        Self::recommit_range(
+            &this.page_alloc,
+            &mut page.decommitted,
+            start_addr,
+            start_addr + layout.size(),
+        );
+        let dirty_len = Self::mark_used(&mut page.pristine, start_addr, start_addr + layout.size());
 
-        None
+        let ptr = NonNull::new(start_addr as *mut u8).unwrap();
+        Some((NonNull::slice_from_raw_parts(ptr, layout.size()), dirty_len))
     }
 
-    fn alloc_in_new_page(this: &mut InnerLocalAlloc, page: Slice, layout: Layout) -> NonNull<[u8]> {
+    fn initial_pristine(page_alloc: &P, page: Slice) -> Ptr {
+        if page_alloc.zeroes_pages() {
+            page.ptr
+        } else {
+            page.ptr + page.len
+        }
+    }
+
+    /// Marks the range [start, end) of a page as used and returns the number of bytes at the start
+    /// of it that are not known to be zero.
+    fn mark_used(pristine: &mut Ptr, start: usize, end: usize) -> usize {
+        let dirty_end = (*pristine).clamp(start, end);
+        *pristine = (*pristine).max(end);
+        dirty_end - start
+    }
+
+    /// Returns the allocation and the number of bytes at the start of it that are not known to be zero.
+    fn alloc_in_new_page(
+        this: &mut InnerLocalAlloc<P>,
+        page: Slice,
+        layout: Layout,
+    ) -> (NonNull<[u8]>, usize) {
         assert_ne!(layout.size(), 0);
         assert!(page.len >= layout.size());
         assert_eq!(align_offset(page.ptr, layout.align()), 0);
 
-        this.pages.push(page);
-
-        let mut free_ranges = Vec::new();
         if layout.size() < page.len {
-            free_ranges.push(Slice {
+            this.free_ranges.insert(Slice {
                 ptr: page.ptr + layout.size(),
                 len: page.len - layout.size(),
             });
         }
-        this.free_list.push(free_ranges);
+        let mut pristine = Self::initial_pristine(&this.page_alloc, page);
+        let dirty_len = Self::mark_used(&mut pristine, page.ptr, page.ptr + layout.size());
+        this.pages.insert(
+            page.ptr,
+            Page {
+                ptr: page.ptr,
+                len: page.len,
+                decommitted: BTreeMap::new(),
+                pristine,
+            },
+        );
 
         let ptr = NonNull::new(page.ptr as *mut u8).unwrap();
-        NonNull::slice_from_raw_parts(ptr, layout.size())
+        (NonNull::slice_from_raw_parts(ptr, layout.size()), dirty_len)
     }
 
-    fn free_pages_if_needed(this: &mut InnerLocalAlloc) {
-        if this.free_after >= this.total_page_size {
+    /// Recommits the parts of the decommitted ranges that overlap with the range [start, end).
+    fn recommit_range(
+        page_alloc: &P,
+        decommitted: &mut BTreeMap<Ptr, usize>,
+        start: usize,
+        end: usize,
+    ) {
+        let start = align_down(start, 1 << 12);
+        let end = align_up(end, 1 << 12);
+
+        for range in ranges_touching(decommitted, start, end) {
+            let range_end = range.ptr + range.len;
+            let overlap_start = range.ptr.max(start);
+            let overlap_end = range_end.min(end);
+            if overlap_start >= overlap_end {
+                continue;
+            }
+
+            decommitted.remove(&range.ptr);
+            if range.ptr < overlap_start {
+                decommitted.insert(range.ptr, overlap_start - range.ptr);
+            }
+            if overlap_end < range_end {
+                decommitted.insert(overlap_end, range_end - overlap_end);
+            }
+
+            let overlap = Slice {
+                ptr: overlap_start,
+                len: overlap_end - overlap_start,
+            };
+            // Safety: overlap is a 4KB aligned part of a range we decommitted using the same page_alloc.
+            unsafe { page_alloc.recommit(overlap.to_page()) };
+        }
+    }
+
+    /// Decommits the parts of the 4KB aligned part of free_range that aren't decommitted yet,
+    /// if free_range is big enough.
+    ///
+    /// Only the ranges in page.decommitted are known to be decommitted, so freeing next to a big
+    /// decommitted range doesn't decommit that range again but a big range that was never
+    /// decommitted (like the unused tail of a new page) is.
+    fn decommit_free_range(
+        page_alloc: &P,
+        page: &mut Page,
+        decommit_threshold: usize,
+        free_range: Slice,
+    ) {
+        if free_range.len < decommit_threshold {
             return;
         }
 
-        let mut page_index = 0;
-
-        // try to find any empty pages and free them
-        // this loop pattern is used because we need to remove items while iterating
-        while page_index < this.pages.len() {
-            let free_r = this.free_list.get_mut(page_index).unwrap();
-            if free_r.len() == 1 {
-                let range = *free_r.first().unwrap();
-                let page = *this.pages.get(page_index).unwrap();
-                if range.ptr == page.ptr && range.len == page.len {
-                    this.pages.swap_remove(page_index);
-                    this.free_list.swap_remove(page_index);
-
-                    this.total_page_size -= page.len;
-
-                    let ptr = NonNull::new(page.ptr as *mut u8).unwrap();
-                    let page = NonNull::slice_from_raw_parts(ptr, page.len);
-                    // Safety: we allocate these pages with the same page alloc.
-                    // page and it's free_ranges are removed from the data structure immediately
-                    // before freeing the page.
-                    unsafe { this.page_alloc.dealloc_page(page) };
-
-                    continue;
-                }
+        let start = align_up(free_range.ptr, 1 << 12);
+        let end = align_down(free_range.ptr + free_range.len, 1 << 12);
+        if start >= end {
+            return;
+        }
+
+        let mut decommitted = ranges_touching(&page.decommitted, start, end);
+        decommitted.reverse();
+        let decommit_gap = |gap_start: Ptr, gap_end: Ptr| {
+            if gap_start < gap_end {
+                let gap = Slice {
+                    ptr: gap_start,
+                    len: gap_end - gap_start,
+                };
+                // Safety: gap is a 4KB aligned part of a free range inside a page we allocated with page_alloc.
+                // Nothing references it since it is free.
+                unsafe { page_alloc.decommit(gap.to_page()) };
             }
-            page_index += 1;
+        };
+        let mut gap_start = start;
+        for range in &decommitted {
+            decommit_gap(gap_start, range.ptr.min(end));
+            gap_start = gap_start.max(range.ptr + range.len);
         }
+        decommit_gap(gap_start, end);
+
+        let (mut merged_start, mut merged_end) = (start, end);
+        for range in decommitted {
+            page.decommitted.remove(&range.ptr);
+            merged_start = merged_start.min(range.ptr);
+            merged_end = merged_end.max(range.ptr + range.len);
+        }
+        page.decommitted
+            .insert(merged_start, merged_end - merged_start);
+        // decommitted memory isn't known to be zero after it is recommitted
+        page.pristine = page.pristine.max(end);
     }
 
-    fn alloc(this: &mut InnerLocalAlloc, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+    /// Grows the page that contains the allocation using the page allocator, if the allocation
+    /// is the only thing that lives in that page.
+    fn try_grow_dedicated_page(
+        this: &mut InnerLocalAlloc<P>,
+        ptr: Ptr,
+        old_size: usize,
+        new_size: usize,
+    ) -> Option<NonNull<[u8]>> {
         if this.error_after <= this.total_page_size {
-            return Err(AllocError);
+            return None;
+        }
+
+        let page = this.pages.get(&ptr)?.slice();
+        // Adjacent free ranges are always merged, so the rest of the page has to be a single free range.
+        let rest = if old_size < page.len {
+            let rest = this.free_ranges.starting_at(ptr + old_size)?;
+            if old_size + rest.len != page.len {
+                return None;
+            }
+            Some(rest)
+        } else {
+            None
+        };
+
+        // Safety: page was allocated with page_alloc and the only allocation that lives in it
+        // is the one we are growing.
+        let new_page = unsafe { this.page_alloc.grow_page(page.to_page(), new_size) }.ok()?;
+        let new_page = Slice {
+            ptr: new_page.cast::<u8>().as_ptr() as usize,
+            len: new_page.len(),
+        };
+
+        this.total_page_size = this.total_page_size - page.len + new_page.len;
+        // grow_page doesn't guarantee anything about the contents of the grown part
+        this.pages.remove(&page.ptr);
+        this.pages.insert(
+            new_page.ptr,
+            Page {
+                ptr: new_page.ptr,
+                len: new_page.len,
+                decommitted: BTreeMap::new(),
+                pristine: new_page.ptr + new_page.len,
+            },
+        );
+        if let Some(rest) = rest {
+            this.free_ranges.remove(rest);
+        }
+        if new_page.len > new_size {
+            this.free_ranges.insert(Slice {
+                ptr: new_page.ptr + new_size,
+                len: new_page.len - new_size,
+            });
         }
+        this.ptr_to_size.insert(new_page.ptr, new_size);
 
-        // Alignment over 4KB is not allowed
-        if layout.align() > 1 << 12 {
+        let ptr = NonNull::new(new_page.ptr as *mut u8).unwrap();
+        Some(NonNull::slice_from_raw_parts(ptr, new_size))
+    }
+
+    fn free_pages_if_needed(this: &mut InnerLocalAlloc<P>) {
+        if this.free_after >= this.total_page_size {
+            return;
+        }
+
+        while let Some(page_ptr) = this.empty_pages.pop_first() {
+            let page = this.pages.remove(&page_ptr).unwrap().slice();
+            this.free_ranges.remove(page);
+            this.total_page_size -= page.len;
+
+            // Safety: we allocate these pages with the same page alloc.
+            // page and it's free range are removed from the data structure immediately
+            // before freeing the page.
+            unsafe { this.page_alloc.dealloc_page(page.to_page()) };
+        }
+    }
+
+    /// Returns the allocation and the number of bytes at the start of it that are not known to be zero.
+    fn alloc(
+        this: &mut InnerLocalAlloc<P>,
+        layout: Layout,
+    ) -> Result<(NonNull<[u8]>, usize), AllocError> {
+        if this.error_after <= this.total_page_size {
             return Err(AllocError);
         }
 
         if layout.size() == 0 {
-            return Ok(NonNull::slice_from_raw_parts(NonNull::dangling(), 0));
+            return Ok((NonNull::slice_from_raw_parts(NonNull::dangling(), 0), 0));
         }
 
-        if let Some(res) = Self::try_alloc_in_existing_pages(this, layout) {
+        if let Some((res, dirty_len)) = Self::try_alloc_in_existing_pages(this, layout) {
             this.ptr_to_size
-                .push((res.cast::<u8>().as_ptr() as usize, res.len()));
-            return Ok(res);
+                .insert(res.cast::<u8>().as_ptr() as usize, res.len());
+            return Ok((res, dirty_len));
         }
 
         let page_alloc_size = layout.size().max(this.min_page_size);
-        let page = this.page_alloc.alloc_page(page_alloc_size)?;
+        let page = this
+            .page_alloc
+            .alloc_page_aligned(page_alloc_size, layout.align().max(1 << 12))?;
         let page = Slice {
             ptr: page.cast::<u8>().as_ptr() as usize,
             len: page.len(),
         };
         this.total_page_size += page.len;
 
-        let x = Self::alloc_in_new_page(this, page, layout);
+        let (x, dirty_len) = Self::alloc_in_new_page(this, page, layout);
         this.ptr_to_size
-            .push((x.cast::<u8>().as_ptr() as usize, x.len()));
+            .insert(x.cast::<u8>().as_ptr() as usize, x.len());
 
-        Ok(x)
+        Ok((x, dirty_len))
     }
 
-    fn dealloc(this: &mut InnerLocalAlloc, ptr: NonNull<u8>, size: usize) {
+    fn dealloc(this: &mut InnerLocalAlloc<P>, ptr: NonNull<u8>, size: usize) {
         if size == 0 {
             return;
         }
 
         let addr = ptr.as_ptr() as usize;
-        let size_idx = this
+        let size = this
             .ptr_to_size
-            .iter()
-            .position(|x| x.0 == addr)
+            .remove(&addr)
             .expect("find allocation index");
-        let size = this.ptr_to_size.swap_remove(size_idx).1;
 
         let start_addr = ptr.as_ptr() as usize;
         let end_addr = start_addr + size;
 
-        for page_idx in 0..this.pages.len() {
-            {
-                let page = *this.pages.get(page_idx).unwrap();
-                let contains = start_addr >= page.ptr && page.ptr + page.len >= end_addr;
-                if !contains {
-                    continue;
-                }
-            }
+        let page = page_containing(&mut this.pages, start_addr)
+            .filter(|page| page.ptr + page.len >= end_addr)
+            .expect("bad deallocate");
 
-            let free_ranges = this.free_list.get_mut(page_idx).unwrap();
-            let mut range_to_insert = Slice {
-                ptr: start_addr,
-                len: size,
-            };
-            let mut free_range_idx = 0;
-            let mut found = false;
-            // Try to find adjacent free ranges to the free range we want to insert.
-            // We might have two such ranges, one to the left of our range and one to the right.
-            while free_range_idx < free_ranges.len() {
-                let free_range = *free_ranges.get(free_range_idx).unwrap();
-                if free_range.ptr == end_addr {
-                    range_to_insert = Slice {
-                        ptr: range_to_insert.ptr,
-                        len: range_to_insert.len + free_range.len,
-                    };
-                    free_ranges.swap_remove(free_range_idx);
-                    if found {
-                        break;
-                    }
-                    found = true;
-                } else if free_range.ptr + free_range.len == start_addr {
-                    range_to_insert = Slice {
-                        ptr: free_range.ptr,
-                        len: range_to_insert.len + free_range.len,
-                    };
-                    free_ranges.swap_remove(free_range_idx);
-                    if found {
-                        break;
-                    }
-                    found = true;
-                } else {
-                    free_range_idx += 1;
-                }
+        // Merge with the free ranges right before and right after the deallocated range.
+        // Pages can be next to each other in memory, so only ranges in the same page are merged.
+        let mut range_to_insert = Slice {
+            ptr: start_addr,
+            len: size,
+        };
+        if end_addr < page.ptr + page.len {
+            if let Some(next) = this.free_ranges.starting_at(end_addr) {
+                this.free_ranges.remove(next);
+                range_to_insert.len += next.len;
+            }
+        }
+        if start_addr > page.ptr {
+            if let Some(prev) = this.free_ranges.ending_at(start_addr) {
+                this.free_ranges.remove(prev);
+                range_to_insert = Slice {
+                    ptr: prev.ptr,
+                    len: prev.len + range_to_insert.len,
+                };
             }
-
-            free_ranges.push(range_to_insert);
-
-            Self::free_pages_if_needed(this);
-
-            return;
         }
+        this.free_ranges.insert(range_to_insert);
+        if range_to_insert.ptr == page.ptr && range_to_insert.len == page.len {
+            this.empty_pages.insert(page.ptr);
+        }
+
+        Self::decommit_free_range(
+            &this.page_alloc,
+            page,
+            this.decommit_threshold,
+            range_to_insert,
+        );
 
-        panic!("bad deallocate");
+        Self::free_pages_if_needed(this);
     }
 }
 
 // Safety: pointers given by local alloc point to actual pages and not to inside the struct itself.
 // So it is safe to move a LocalAlloc while there are live allocations on it.
-unsafe impl Allocator for LocalAlloc<'_> {
+unsafe impl<P: PageAlloc> Allocator for LocalAlloc<P> {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         let mut this = self.inner.borrow_mut();
         let this = this.deref_mut();
-        Self::alloc(this, layout)
+        Self::alloc(this, layout).map(|(ptr, _)| ptr)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut this = self.inner.borrow_mut();
+        let this = this.deref_mut();
+        let (ptr, dirty_len) = Self::alloc(this, layout)?;
+        // Safety: memory was just allocated and dirty_len is at most the size of it.
+        unsafe { ptr.cast::<u8>().as_ptr().write_bytes(0, dirty_len) };
+        Ok(ptr)
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
@@ -323,46 +726,64 @@ unsafe impl Allocator for LocalAlloc<'_> {
             let this = this.deref_mut();
 
             if old_layout.size() == 0 && new_layout.size() > 0 {
-                return Self::alloc(this, new_layout);
+                return Self::alloc(this, new_layout).map(|(ptr, _)| ptr);
             }
 
             if old_layout.size() > 0 {
-                let idx = this
+                let size = this
                     .ptr_to_size
-                    .iter()
-                    .position(|x| x.0 == ptr.as_ptr() as usize)
+                    .remove(&(ptr.as_ptr() as usize))
                     .expect("find old alloc size");
-                let size = this.ptr_to_size.remove(idx).1;
                 old_layout = Layout::from_size_align(size, old_layout.align()).unwrap();
             }
 
             let end_addr = (ptr.as_ptr() as usize) + old_layout.size();
 
-            'try_alloc: for free_ranges in this.free_list.iter_mut() {
-                for free_range_idx in 0..free_ranges.len() {
-                    let free_range = *free_ranges.get(free_range_idx).unwrap();
-                    if free_range.ptr == end_addr {
-                        if free_range.len + old_layout.size() > new_layout.size() {
-                            let size_diff = new_layout.size() - old_layout.size();
-                            free_ranges[free_range_idx].len -= size_diff;
-                            this.ptr_to_size
-                                .push((ptr.as_ptr() as usize, new_layout.size()));
-                            return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
-                        } else if free_range.len + old_layout.size() == new_layout.size() {
-                            free_ranges.swap_remove(free_range_idx);
-                            this.ptr_to_size
-                                .push((ptr.as_ptr() as usize, new_layout.size()));
-                            return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
-                        } else {
-                            break 'try_alloc;
-                        }
-                    }
+            // Pages can be next to each other in memory, so the free range has to be in the
+            // same page as the allocation.
+            let page = page_containing(&mut this.pages, ptr.as_ptr() as usize).unwrap();
+            let free_range = if end_addr < page.ptr + page.len {
+                this.free_ranges.starting_at(end_addr)
+            } else {
+                None
+            };
+            let size_diff = new_layout.size() - old_layout.size();
+            if let Some(free_range) = free_range.filter(|range| range.len >= size_diff) {
+                this.free_ranges.remove(free_range);
+                if free_range.len > size_diff {
+                    this.free_ranges.insert(Slice {
+                        ptr: free_range.ptr + size_diff,
+                        len: free_range.len - size_diff,
+                    });
+                }
+                Self::recommit_range(
+                    &this.page_alloc,
+                    &mut page.decommitted,
+                    end_addr,
+                    end_addr + size_diff,
+                );
+                Self::mark_used(&mut page.pristine, end_addr, end_addr + size_diff);
+
+                this.ptr_to_size
+                    .insert(ptr.as_ptr() as usize, new_layout.size());
+                return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+            }
+
+            // Pages can only be relied on to be 4KB aligned after they are resized
+            if new_layout.align() <= 1 << 12 {
+                if let Some(res) = Self::try_grow_dedicated_page(
+                    this,
+                    ptr.as_ptr() as usize,
+                    old_layout.size(),
+                    new_layout.size(),
+                ) {
+                    return Ok(res);
                 }
             }
 
             if old_layout.size() > 0 {
                 this.ptr_to_size
-                    .push((ptr.as_ptr() as usize, old_layout.size()));
+                    .insert(ptr.as_ptr() as usize, old_layout.size());
             }
         } // end "this" scope
 
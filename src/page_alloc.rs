@@ -1,6 +1,23 @@
 use core::alloc::{AllocError, Layout};
 use core::ptr::NonNull;
 use std::alloc::Allocator;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+pub mod allocator_page_alloc;
+pub mod budget_page_alloc;
+pub mod caching_page_alloc;
+pub mod fallback_page_alloc;
+#[cfg(target_os = "linux")]
+pub mod file_page_alloc;
+pub mod fixed_buffer_page_alloc;
+#[cfg(target_os = "linux")]
+pub mod guarded_page_alloc;
+#[cfg(target_os = "linux")]
+pub mod memfd_page_alloc;
+#[cfg(target_os = "linux")]
+pub mod region_page_alloc;
 
 /// # Safety
 ///
@@ -8,25 +25,219 @@ use std::alloc::Allocator;
 pub unsafe trait PageAlloc {
     /// Returns a pointer aligned to at least 4KB
     fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError>;
+    /// Returns true if the pages returned by alloc_page and alloc_page_aligned are always zeroed.
+    ///
+    /// The default implementation returns false.
+    fn zeroes_pages(&self) -> bool {
+        false
+    }
+    /// Returns a pointer aligned to at least align, which has to be a power of two.
+    ///
+    /// The default implementation only supports alignments up to 4KB.
+    fn alloc_page_aligned(&self, size: usize, align: usize) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(align.is_power_of_two());
+        if align > 1 << 12 {
+            return Err(AllocError);
+        }
+        self.alloc_page(size)
+    }
     /// # Safety
     ///
-    /// page has to be a currently allocated page from this instance of PageAlloc
+    /// page has to be a currently allocated page from this instance of PageAlloc.
+    /// The page can contain decommitted ranges.
     unsafe fn dealloc_page(&self, page: NonNull<[u8]>);
+    /// Resizes page so it is at least new_size bytes, preserving its contents.
+    /// The returned page is aligned to at least 4KB and it doesn't contain any decommitted ranges.
+    ///
+    /// The default implementation allocates a new page and copies the contents into it.
+    ///
+    /// # Safety
+    ///
+    /// page has to be a currently allocated page from this instance of PageAlloc and new_size
+    /// has to be bigger than page.len(). If this function succeeds the old page is invalidated.
+    unsafe fn grow_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(new_size > page.len());
+        realloc_page_by_copy(self, page, new_size)
+    }
+    /// Resizes page so it is at least new_size bytes, preserving the first new_size bytes of its contents.
+    /// The returned page is aligned to at least 4KB and it doesn't contain any decommitted ranges.
+    ///
+    /// The default implementation allocates a new page and copies the contents into it.
+    ///
+    /// # Safety
+    ///
+    /// page has to be a currently allocated page from this instance of PageAlloc and new_size
+    /// has to be smaller than page.len(). If this function succeeds the old page is invalidated.
+    unsafe fn shrink_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(new_size < page.len());
+        assert!(new_size > 0);
+        realloc_page_by_copy(self, page, new_size)
+    }
+    /// Returns true if decommit gives the memory of the range back to the OS.
+    ///
+    /// The default implementation returns false.
+    fn decommits(&self) -> bool {
+        false
+    }
+    /// Tells the page allocator that the contents of range are not needed anymore,
+    /// so the memory backing it can be given back to the OS.
+    ///
+    /// The default implementation does nothing.
+    ///
+    /// # Safety
+    ///
+    /// range has to be inside a currently allocated page from this instance of PageAlloc and
+    /// both its start and its length have to be multiples of 4KB.
+    /// Contents of the range are undefined after this call and the range can't be accessed
+    /// until recommit is called on it.
+    unsafe fn decommit(&self, _range: NonNull<[u8]>) {}
+    /// Makes a decommitted range accessible again.
+    ///
+    /// The default implementation does nothing.
+    ///
+    /// # Safety
+    ///
+    /// range has to be inside a currently allocated page from this instance of PageAlloc and
+    /// both its start and its length have to be multiples of 4KB.
+    /// Recommitting a range that isn't decommitted is allowed.
+    unsafe fn recommit(&self, _range: NonNull<[u8]>) {}
+    /// Faults in the memory of range, so the first writes to it don't cause page faults.
+    ///
+    /// The default implementation writes back the first byte of every 4KB of the range.
+    ///
+    /// # Safety
+    ///
+    /// range has to be inside a currently allocated page from this instance of PageAlloc and
+    /// both its start and its length have to be multiples of 4KB. It shouldn't contain decommitted ranges
+    /// and it can't be accessed concurrently with this call.
+    unsafe fn prefault(&self, range: NonNull<[u8]>) {
+        let ptr = range.cast::<u8>().as_ptr();
+        for offset in (0..range.len()).step_by(1 << 12) {
+            let ptr = ptr.add(offset);
+            std::ptr::write_volatile(ptr, std::ptr::read_volatile(ptr));
+        }
+    }
 }
 
+/// Moves the contents of page into a newly allocated page of new_size bytes.
+///
+/// # Safety
+///
+/// page has to be a currently allocated page from page_alloc. The old page is invalidated if this function succeeds.
+pub(crate) unsafe fn realloc_page_by_copy<P: PageAlloc + ?Sized>(
+    page_alloc: &P,
+    page: NonNull<[u8]>,
+    new_size: usize,
+) -> Result<NonNull<[u8]>, AllocError> {
+    let new_page = page_alloc.alloc_page(new_size)?;
+    page_alloc.recommit(page);
+    std::ptr::copy_nonoverlapping(
+        page.cast::<u8>().as_ptr(),
+        new_page.cast::<u8>().as_ptr(),
+        page.len().min(new_size),
+    );
+    page_alloc.dealloc_page(page);
+    Ok(new_page)
+}
+
+// Alignment of the pages allocated from Global with an alignment above 4KB, by start address.
+// Global is stateless, but deallocating needs the alignment the page was allocated with.
+static GLOBAL_ALIGNED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
 unsafe impl PageAlloc for std::alloc::Global {
     fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError> {
         let alloc_size = size.next_multiple_of(1 << 12);
         let layout = Layout::from_size_align(alloc_size, 1 << 12).unwrap();
         self.allocate(layout)
     }
+    fn alloc_page_aligned(&self, size: usize, align: usize) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(align.is_power_of_two());
+        if align <= 1 << 12 {
+            return self.alloc_page(size);
+        }
+        let layout = Layout::from_size_align(size.next_multiple_of(1 << 12), align).unwrap();
+        let page = self.allocate(layout)?;
+        GLOBAL_ALIGNED_PAGES
+            .lock()
+            .unwrap()
+            .insert(page.cast::<u8>().as_ptr() as usize, align);
+        Ok(page)
+    }
     unsafe fn dealloc_page(&self, page: NonNull<[u8]>) {
+        let align = GLOBAL_ALIGNED_PAGES
+            .lock()
+            .unwrap()
+            .remove(&(page.cast::<u8>().as_ptr() as usize))
+            .unwrap_or(1 << 12);
         self.deallocate(
             page.cast::<u8>(),
-            Layout::from_size_align(page.len(), 1 << 12).unwrap(),
+            Layout::from_size_align(page.len(), align).unwrap(),
         );
     }
 }
 
+macro_rules! impl_page_alloc_for_pointer {
+    ($($pointer:ty),*) => {
+        $(
+            // Safety: moving the pointer doesn't move the page allocator it points to.
+            unsafe impl<P: PageAlloc + ?Sized> PageAlloc for $pointer {
+                fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+                    (**self).alloc_page(size)
+                }
+                fn zeroes_pages(&self) -> bool {
+                    (**self).zeroes_pages()
+                }
+                fn alloc_page_aligned(
+                    &self,
+                    size: usize,
+                    align: usize,
+                ) -> Result<NonNull<[u8]>, AllocError> {
+                    (**self).alloc_page_aligned(size, align)
+                }
+                unsafe fn dealloc_page(&self, page: NonNull<[u8]>) {
+                    (**self).dealloc_page(page)
+                }
+                unsafe fn grow_page(
+                    &self,
+                    page: NonNull<[u8]>,
+                    new_size: usize,
+                ) -> Result<NonNull<[u8]>, AllocError> {
+                    (**self).grow_page(page, new_size)
+                }
+                unsafe fn shrink_page(
+                    &self,
+                    page: NonNull<[u8]>,
+                    new_size: usize,
+                ) -> Result<NonNull<[u8]>, AllocError> {
+                    (**self).shrink_page(page, new_size)
+                }
+                fn decommits(&self) -> bool {
+                    (**self).decommits()
+                }
+                unsafe fn decommit(&self, range: NonNull<[u8]>) {
+                    (**self).decommit(range)
+                }
+                unsafe fn recommit(&self, range: NonNull<[u8]>) {
+                    (**self).recommit(range)
+                }
+                unsafe fn prefault(&self, range: NonNull<[u8]>) {
+                    (**self).prefault(range)
+                }
+            }
+        )*
+    };
+}
+
+impl_page_alloc_for_pointer!(&P, Box<P>, Rc<P>, Arc<P>);
+
 #[cfg_attr(target_os = "linux", path = "./page_alloc/linux.rs")]
-mod dynamic_page_alloc;
+pub mod dynamic_page_alloc;
+pub use dynamic_page_alloc::DynamicPageAlloc;
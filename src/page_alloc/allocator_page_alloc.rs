@@ -0,0 +1,80 @@
+use core::alloc::{AllocError, Allocator, Layout};
+use core::ptr::NonNull;
+
+use super::PageAlloc;
+use crate::util::align_down;
+
+/// Page allocator that allocates pages from an [Allocator].
+///
+/// Page sizes are rounded up to a multiple of 4KB and pages are aligned to 4KB,
+/// bigger alignments are not supported.
+/// This makes it possible to build an allocator on top of another one, for example a
+/// [crate::local_alloc::LocalAlloc] on top of a [crate::bump_alloc::BumpAlloc].
+pub struct AllocatorPageAlloc<A: Allocator> {
+    alloc: A,
+}
+
+impl<A: Allocator> AllocatorPageAlloc<A> {
+    pub fn new(alloc: A) -> Self {
+        Self { alloc }
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+}
+
+fn page_layout(size: usize) -> Layout {
+    Layout::from_size_align(size.next_multiple_of(1 << 12), 1 << 12).unwrap()
+}
+
+/// Trims the slice returned by the allocator to a multiple of 4KB.
+/// It still fits the layout it was allocated with since that size is a multiple of 4KB.
+fn to_page(ptr: NonNull<[u8]>) -> NonNull<[u8]> {
+    NonNull::slice_from_raw_parts(ptr.cast::<u8>(), align_down(ptr.len(), 1 << 12))
+}
+
+// Safety: the allocator is required to not invalidate allocations when it is moved.
+unsafe impl<A: Allocator> PageAlloc for AllocatorPageAlloc<A> {
+    fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(size > 0);
+        self.alloc.allocate(page_layout(size)).map(to_page)
+    }
+
+    unsafe fn dealloc_page(&self, page: NonNull<[u8]>) {
+        self.alloc
+            .deallocate(page.cast::<u8>(), page_layout(page.len()));
+    }
+
+    unsafe fn grow_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(new_size > page.len());
+        self.alloc
+            .grow(
+                page.cast::<u8>(),
+                page_layout(page.len()),
+                page_layout(new_size),
+            )
+            .map(to_page)
+    }
+
+    unsafe fn shrink_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(new_size < page.len());
+        assert!(new_size > 0);
+
+        let new_layout = page_layout(new_size);
+        if new_layout.size() == page.len() {
+            return Ok(page);
+        }
+        self.alloc
+            .shrink(page.cast::<u8>(), page_layout(page.len()), new_layout)
+            .map(to_page)
+    }
+}
@@ -0,0 +1,247 @@
+use core::alloc::AllocError;
+use core::ptr::NonNull;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use super::PageAlloc;
+
+pub struct Config<P: PageAlloc> {
+    page_alloc: P,
+    limit: usize,
+}
+
+impl<P: PageAlloc> Config<P> {
+    pub fn new(page_alloc: P, limit: usize) -> Self {
+        Self { page_alloc, limit }
+    }
+}
+
+/// Page allocator that limits the total size of the committed pages allocated through it.
+///
+/// It is meant to be shared between threads so a single limit can be applied to many
+/// thread local allocators.
+///
+/// The limit is checked using the requested sizes, usage is counted using the lengths of the pages
+/// returned by the underlying allocator. So rounding done by the underlying allocator can make the
+/// usage go slightly above the limit.
+/// Ranges decommitted through an underlying allocator that gives their memory back to the OS
+/// don't count towards the usage.
+/// Recommitting can't fail, so the limit is a soft cap for it: recommitting can take the usage above
+/// the limit, which is recorded in peak_usage, and allocations fail until the usage is below it again.
+pub struct BudgetPageAlloc<P: PageAlloc> {
+    page_alloc: P,
+    limit: usize,
+    usage: AtomicUsize,
+    peak_usage: AtomicUsize,
+    decommitted: Mutex<DecommittedRanges>,
+}
+
+/// Disjoint decommitted ranges, maps the start of each range to its end.
+#[derive(Default)]
+struct DecommittedRanges(BTreeMap<usize, usize>);
+
+impl DecommittedRanges {
+    /// Calls f with the start and end of every range overlapping start..end, from the last one.
+    fn for_each_overlapping(&self, start: usize, end: usize, mut f: impl FnMut(usize, usize)) {
+        for (&range_start, &range_end) in self.0.range(..end).rev() {
+            if range_end <= start {
+                break;
+            }
+            f(range_start, range_end);
+        }
+    }
+
+    /// Returns the number of decommitted bytes in start..end.
+    fn overlap(&self, start: usize, end: usize) -> usize {
+        let mut bytes = 0;
+        self.for_each_overlapping(start, end, |range_start, range_end| {
+            bytes += range_end.min(end) - range_start.max(start);
+        });
+        bytes
+    }
+
+    /// Marks start..end as decommitted and returns the number of bytes that weren't already.
+    fn insert(&mut self, start: usize, end: usize) -> usize {
+        let mut overlapping = Vec::new();
+        self.for_each_overlapping(start, end, |range_start, range_end| {
+            overlapping.push((range_start, range_end))
+        });
+        let mut bytes = end - start;
+        let (mut new_start, mut new_end) = (start, end);
+        for (range_start, range_end) in overlapping {
+            self.0.remove(&range_start);
+            bytes -= range_end.min(end) - range_start.max(start);
+            new_start = new_start.min(range_start);
+            new_end = new_end.max(range_end);
+        }
+        self.0.insert(new_start, new_end);
+        bytes
+    }
+
+    /// Marks start..end as committed and returns the number of bytes that were decommitted.
+    fn remove(&mut self, start: usize, end: usize) -> usize {
+        let mut overlapping = Vec::new();
+        self.for_each_overlapping(start, end, |range_start, range_end| {
+            overlapping.push((range_start, range_end))
+        });
+        let mut bytes = 0;
+        for (range_start, range_end) in overlapping {
+            self.0.remove(&range_start);
+            bytes += range_end.min(end) - range_start.max(start);
+            if range_start < start {
+                self.0.insert(range_start, start);
+            }
+            if range_end > end {
+                self.0.insert(end, range_end);
+            }
+        }
+        bytes
+    }
+}
+
+fn bounds(range: NonNull<[u8]>) -> (usize, usize) {
+    let start = range.cast::<u8>().as_ptr() as usize;
+    (start, start + range.len())
+}
+
+impl<P: PageAlloc> BudgetPageAlloc<P> {
+    pub fn new(config: Config<P>) -> Self {
+        Self {
+            page_alloc: config.page_alloc,
+            limit: config.limit,
+            usage: AtomicUsize::new(0),
+            peak_usage: AtomicUsize::new(0),
+            decommitted: Mutex::new(DecommittedRanges::default()),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Total size of the pages that are currently allocated, without their decommitted ranges.
+    pub fn usage(&self) -> usize {
+        self.usage.load(Ordering::Relaxed)
+    }
+
+    /// Highest value usage has reached.
+    pub fn peak_usage(&self) -> usize {
+        self.peak_usage.load(Ordering::Relaxed)
+    }
+
+    fn reserve(&self, size: usize) -> Result<(), AllocError> {
+        let prev = self
+            .usage
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+                usage.checked_add(size).filter(|&x| x <= self.limit)
+            })
+            .map_err(|_| AllocError)?;
+        self.peak_usage.fetch_max(prev + size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Accounts for the difference between the reserved size and the actual size of the page.
+    fn adjust(&self, reserved: usize, actual: usize) {
+        if actual > reserved {
+            let prev = self.usage.fetch_add(actual - reserved, Ordering::Relaxed);
+            self.peak_usage
+                .fetch_max(prev + actual - reserved, Ordering::Relaxed);
+        } else {
+            self.usage.fetch_sub(reserved - actual, Ordering::Relaxed);
+        }
+    }
+}
+
+// Safety: pages are owned by the underlying page allocator, which is required to not invalidate them on move.
+unsafe impl<P: PageAlloc> PageAlloc for BudgetPageAlloc<P> {
+    fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc_page_aligned(size, 1 << 12)
+    }
+
+    fn zeroes_pages(&self) -> bool {
+        self.page_alloc.zeroes_pages()
+    }
+
+    fn alloc_page_aligned(&self, size: usize, align: usize) -> Result<NonNull<[u8]>, AllocError> {
+        self.reserve(size)?;
+        match self.page_alloc.alloc_page_aligned(size, align) {
+            Ok(page) => {
+                self.adjust(size, page.len());
+                Ok(page)
+            }
+            Err(e) => {
+                self.adjust(size, 0);
+                Err(e)
+            }
+        }
+    }
+
+    unsafe fn dealloc_page(&self, page: NonNull<[u8]>) {
+        let (start, end) = bounds(page);
+        let decommitted = self.decommitted.lock().unwrap().remove(start, end);
+        self.page_alloc.dealloc_page(page);
+        self.usage
+            .fetch_sub(page.len() - decommitted, Ordering::Relaxed);
+    }
+
+    unsafe fn grow_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        // The returned page doesn't contain decommitted ranges, so they are counted again.
+        let (start, end) = bounds(page);
+        let decommitted = self.decommitted.lock().unwrap().overlap(start, end);
+        let size_diff = new_size - page.len() + decommitted;
+        self.reserve(size_diff)?;
+        match self.page_alloc.grow_page(page, new_size) {
+            Ok(new_page) => {
+                self.decommitted.lock().unwrap().remove(start, end);
+                self.adjust(size_diff, new_page.len() - page.len() + decommitted);
+                Ok(new_page)
+            }
+            Err(e) => {
+                self.adjust(size_diff, 0);
+                Err(e)
+            }
+        }
+    }
+
+    unsafe fn shrink_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new_page = self.page_alloc.shrink_page(page, new_size)?;
+        let (start, end) = bounds(page);
+        let decommitted = self.decommitted.lock().unwrap().remove(start, end);
+        self.adjust(page.len() - decommitted, new_page.len());
+        Ok(new_page)
+    }
+
+    fn decommits(&self) -> bool {
+        self.page_alloc.decommits()
+    }
+
+    unsafe fn decommit(&self, range: NonNull<[u8]>) {
+        self.page_alloc.decommit(range);
+        if !self.page_alloc.decommits() {
+            return;
+        }
+        let (start, end) = bounds(range);
+        let decommitted = self.decommitted.lock().unwrap().insert(start, end);
+        self.usage.fetch_sub(decommitted, Ordering::Relaxed);
+    }
+
+    unsafe fn recommit(&self, range: NonNull<[u8]>) {
+        let (start, end) = bounds(range);
+        let recommitted = self.decommitted.lock().unwrap().remove(start, end);
+        self.adjust(0, recommitted);
+        self.page_alloc.recommit(range);
+    }
+
+    unsafe fn prefault(&self, range: NonNull<[u8]>) {
+        self.page_alloc.prefault(range);
+    }
+}
@@ -0,0 +1,185 @@
+use core::alloc::AllocError;
+use core::ptr::NonNull;
+use std::sync::Mutex;
+
+use super::PageAlloc;
+
+// Use this to avoid creating aliased pointers.
+type Ptr = usize;
+
+#[derive(Clone, Copy)]
+struct Slice {
+    ptr: Ptr,
+    len: usize,
+}
+
+impl Slice {
+    fn to_page(self) -> NonNull<[u8]> {
+        let ptr = NonNull::new(self.ptr as *mut u8).unwrap();
+        NonNull::slice_from_raw_parts(ptr, self.len)
+    }
+}
+
+pub struct Config<P: PageAlloc> {
+    page_alloc: P,
+    max_cached_bytes: usize,
+}
+
+impl<P: PageAlloc> Config<P> {
+    pub fn new(page_alloc: P) -> Self {
+        Self {
+            page_alloc,
+            max_cached_bytes: 1 << 30, // 1 GB
+        }
+    }
+
+    pub fn max_cached_bytes(&mut self, max_cached_bytes: usize) -> &mut Self {
+        self.max_cached_bytes = max_cached_bytes;
+        self
+    }
+}
+
+struct Cache {
+    // Page with length len is in buckets[len.ilog2()]
+    buckets: Vec<Vec<Slice>>,
+    cached_bytes: usize,
+}
+
+/// Page allocator that keeps freed pages around and reuses them instead of returning them to the
+/// underlying page allocator.
+///
+/// Freed pages are cached until the total size of cached pages reaches max_cached_bytes,
+/// pages that don't fit into the cache are freed immediately.
+pub struct CachingPageAlloc<P: PageAlloc> {
+    page_alloc: P,
+    max_cached_bytes: usize,
+    cache: Mutex<Cache>,
+}
+
+impl<P: PageAlloc> CachingPageAlloc<P> {
+    pub fn new(config: Config<P>) -> Self {
+        Self {
+            page_alloc: config.page_alloc,
+            max_cached_bytes: config.max_cached_bytes,
+            cache: Mutex::new(Cache {
+                buckets: (0..usize::BITS).map(|_| Vec::new()).collect(),
+                cached_bytes: 0,
+            }),
+        }
+    }
+
+    /// Total size of the pages that are currently cached.
+    pub fn cached_bytes(&self) -> usize {
+        self.cache.lock().unwrap().cached_bytes
+    }
+
+    /// Frees all cached pages using the underlying page allocator.
+    pub fn trim(&self) {
+        let mut cache = self.cache.lock().unwrap();
+        for bucket in cache.buckets.iter_mut() {
+            for page in bucket.drain(..) {
+                // Safety: cached pages were allocated by self.page_alloc and they are not in use.
+                unsafe { self.page_alloc.dealloc_page(page.to_page()) };
+            }
+        }
+        cache.cached_bytes = 0;
+    }
+
+    fn take_cached(&self, size: usize, align: usize) -> Option<NonNull<[u8]>> {
+        let mut cache = self.cache.lock().unwrap();
+
+        // Only look at the bucket the size falls into and the one above it,
+        // so we don't hand out pages that are a lot bigger than requested.
+        let bucket_idx = size.ilog2() as usize;
+        for idx in bucket_idx..(bucket_idx + 2).min(cache.buckets.len()) {
+            let bucket = cache.buckets.get_mut(idx).unwrap();
+            let pos = bucket
+                .iter()
+                .position(|page| page.len >= size && page.ptr % align == 0);
+            if let Some(pos) = pos {
+                let page = bucket.swap_remove(pos);
+                cache.cached_bytes -= page.len;
+                return Some(page.to_page());
+            }
+        }
+
+        None
+    }
+}
+
+impl<P: PageAlloc> Drop for CachingPageAlloc<P> {
+    fn drop(&mut self) {
+        self.trim();
+    }
+}
+
+// Safety: pages are owned by the underlying page allocator, which is required to not invalidate them on move.
+unsafe impl<P: PageAlloc> PageAlloc for CachingPageAlloc<P> {
+    fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc_page_aligned(size, 1 << 12)
+    }
+
+    fn alloc_page_aligned(&self, size: usize, align: usize) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(size > 0);
+        assert!(align.is_power_of_two());
+
+        match self.take_cached(size, align) {
+            Some(page) => {
+                // The page might have been freed while it had decommitted ranges in it
+                // Safety: page is allocated by self.page_alloc
+                unsafe { self.page_alloc.recommit(page) };
+                Ok(page)
+            }
+            None => self.page_alloc.alloc_page_aligned(size, align),
+        }
+    }
+
+    unsafe fn dealloc_page(&self, page: NonNull<[u8]>) {
+        let mut cache = self.cache.lock().unwrap();
+
+        if cache.cached_bytes + page.len() > self.max_cached_bytes {
+            drop(cache);
+            self.page_alloc.dealloc_page(page);
+            return;
+        }
+
+        cache.cached_bytes += page.len();
+        let bucket_idx = page.len().ilog2() as usize;
+        cache.buckets.get_mut(bucket_idx).unwrap().push(Slice {
+            ptr: page.cast::<u8>().as_ptr() as usize,
+            len: page.len(),
+        });
+    }
+
+    unsafe fn grow_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.page_alloc.grow_page(page, new_size)
+    }
+
+    unsafe fn shrink_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.page_alloc.shrink_page(page, new_size)
+    }
+
+    fn decommits(&self) -> bool {
+        self.page_alloc.decommits()
+    }
+
+    unsafe fn decommit(&self, range: NonNull<[u8]>) {
+        self.page_alloc.decommit(range);
+    }
+
+    unsafe fn recommit(&self, range: NonNull<[u8]>) {
+        self.page_alloc.recommit(range);
+    }
+
+    unsafe fn prefault(&self, range: NonNull<[u8]>) {
+        self.page_alloc.prefault(range);
+    }
+}
@@ -0,0 +1,201 @@
+use core::alloc::AllocError;
+use core::ptr::NonNull;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use super::PageAlloc;
+
+// Use this to avoid creating aliased pointers.
+type Ptr = usize;
+
+#[derive(Clone, Copy)]
+struct Slice {
+    ptr: Ptr,
+    len: usize,
+}
+
+impl Slice {
+    fn from_page(page: NonNull<[u8]>) -> Self {
+        Self {
+            ptr: page.cast::<u8>().as_ptr() as usize,
+            len: page.len(),
+        }
+    }
+}
+
+pub struct Config<A: PageAlloc, B: PageAlloc> {
+    primary: A,
+    fallback: B,
+}
+
+impl<A: PageAlloc, B: PageAlloc> Config<A, B> {
+    pub fn new(primary: A, fallback: B) -> Self {
+        Self { primary, fallback }
+    }
+}
+
+/// Page allocator that allocates pages from the primary page allocator and falls back to the
+/// fallback page allocator if that fails.
+///
+/// Pages that are allocated from the fallback page allocator are tracked, so every page is
+/// returned to the page allocator it came from.
+pub struct FallbackPageAlloc<A: PageAlloc, B: PageAlloc> {
+    primary: A,
+    fallback: B,
+    // Pages that are allocated from the fallback page allocator, maps their start address to their length
+    fallback_pages: Mutex<BTreeMap<Ptr, usize>>,
+    fallback_count: AtomicUsize,
+}
+
+impl<A: PageAlloc, B: PageAlloc> FallbackPageAlloc<A, B> {
+    pub fn new(config: Config<A, B>) -> Self {
+        Self {
+            primary: config.primary,
+            fallback: config.fallback,
+            fallback_pages: Mutex::new(BTreeMap::new()),
+            fallback_count: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    pub fn fallback(&self) -> &B {
+        &self.fallback
+    }
+
+    /// Number of times a page was allocated from the fallback page allocator.
+    pub fn fallback_count(&self) -> usize {
+        self.fallback_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if ptr is inside a page that is allocated from the fallback page allocator.
+    fn is_fallback(&self, ptr: NonNull<[u8]>) -> bool {
+        let ptr = ptr.cast::<u8>().as_ptr() as usize;
+        self.fallback_pages
+            .lock()
+            .unwrap()
+            .range(..=ptr)
+            .next_back()
+            .is_some_and(|(&page_ptr, &len)| ptr < page_ptr + len)
+    }
+
+    fn alloc_fallback(&self, size: usize, align: usize) -> Result<NonNull<[u8]>, AllocError> {
+        let page = self.fallback.alloc_page_aligned(size, align)?;
+        let page_slice = Slice::from_page(page);
+        self.fallback_pages
+            .lock()
+            .unwrap()
+            .insert(page_slice.ptr, page_slice.len);
+        self.fallback_count.fetch_add(1, Ordering::Relaxed);
+        Ok(page)
+    }
+
+    /// Replaces the tracked fallback page that starts at the same address as old_page with new_page.
+    fn replace_fallback_page(&self, old_page: NonNull<[u8]>, new_page: Option<NonNull<[u8]>>) {
+        let old_ptr = old_page.cast::<u8>().as_ptr() as usize;
+        let mut fallback_pages = self.fallback_pages.lock().unwrap();
+        fallback_pages.remove(&old_ptr).expect("find fallback page");
+        if let Some(new_page) = new_page {
+            let new_page = Slice::from_page(new_page);
+            fallback_pages.insert(new_page.ptr, new_page.len);
+        }
+    }
+}
+
+// Safety: pages are owned by the underlying page allocators, which are required to not invalidate them on move.
+unsafe impl<A: PageAlloc, B: PageAlloc> PageAlloc for FallbackPageAlloc<A, B> {
+    fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc_page_aligned(size, 1 << 12)
+    }
+
+    fn zeroes_pages(&self) -> bool {
+        self.primary.zeroes_pages() && self.fallback.zeroes_pages()
+    }
+
+    fn alloc_page_aligned(&self, size: usize, align: usize) -> Result<NonNull<[u8]>, AllocError> {
+        self.primary
+            .alloc_page_aligned(size, align)
+            .or_else(|_| self.alloc_fallback(size, align))
+    }
+
+    unsafe fn dealloc_page(&self, page: NonNull<[u8]>) {
+        if self.is_fallback(page) {
+            self.replace_fallback_page(page, None);
+            self.fallback.dealloc_page(page);
+        } else {
+            self.primary.dealloc_page(page);
+        }
+    }
+
+    unsafe fn grow_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.is_fallback(page) {
+            let new_page = self.fallback.grow_page(page, new_size)?;
+            self.replace_fallback_page(page, Some(new_page));
+            return Ok(new_page);
+        }
+
+        if let Ok(new_page) = self.primary.grow_page(page, new_size) {
+            return Ok(new_page);
+        }
+
+        // Move the page to the fallback page allocator
+        let new_page = self.alloc_fallback(new_size, 1 << 12)?;
+        self.primary.recommit(page);
+        std::ptr::copy_nonoverlapping(
+            page.cast::<u8>().as_ptr(),
+            new_page.cast::<u8>().as_ptr(),
+            page.len(),
+        );
+        self.primary.dealloc_page(page);
+        Ok(new_page)
+    }
+
+    unsafe fn shrink_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.is_fallback(page) {
+            let new_page = self.fallback.shrink_page(page, new_size)?;
+            self.replace_fallback_page(page, Some(new_page));
+            Ok(new_page)
+        } else {
+            self.primary.shrink_page(page, new_size)
+        }
+    }
+
+    fn decommits(&self) -> bool {
+        self.primary.decommits() && self.fallback.decommits()
+    }
+
+    unsafe fn decommit(&self, range: NonNull<[u8]>) {
+        if self.is_fallback(range) {
+            self.fallback.decommit(range);
+        } else {
+            self.primary.decommit(range);
+        }
+    }
+
+    unsafe fn recommit(&self, range: NonNull<[u8]>) {
+        if self.is_fallback(range) {
+            self.fallback.recommit(range);
+        } else {
+            self.primary.recommit(range);
+        }
+    }
+
+    unsafe fn prefault(&self, range: NonNull<[u8]>) {
+        if self.is_fallback(range) {
+            self.fallback.prefault(range);
+        } else {
+            self.primary.prefault(range);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use core::alloc::AllocError;
+use core::ptr::NonNull;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::PageAlloc;
+use crate::util::align_up;
+
+// Use this to avoid creating aliased pointers.
+type Ptr = usize;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"s_alloc\0");
+const HEADER_SIZE: usize = 1 << 12;
+
+/// Stored at the start of the file
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Header {
+    magic: u64,
+    base_addr: u64,
+    capacity: u64,
+    // Number of bytes after the header that are used by pages
+    used: u64,
+    root: u64,
+}
+
+pub struct Config {
+    path: PathBuf,
+    capacity: usize,
+    base_addr: Option<usize>,
+}
+
+impl Config {
+    /// capacity is the maximum size of the file, it is rounded up to a multiple of 4KB.
+    pub fn new(path: impl AsRef<Path>, capacity: usize) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            capacity,
+            base_addr: None,
+        }
+    }
+
+    /// Address to map the file at. By default the kernel chooses the address when the file is created.
+    pub fn base_addr(&mut self, base_addr: usize) -> &mut Self {
+        self.base_addr = Some(base_addr);
+        self
+    }
+}
+
+/// Page allocator that allocates pages from a file that is mapped at a fixed address.
+///
+/// The address is recorded in the file, so it can be reopened at the same address later using [FilePageAlloc::open].
+/// Pointers stored inside the pages stay valid across reopens.
+///
+/// Pages are never reused, freeing a page does nothing, since the data in the pages has to persist after the
+/// allocators built on top of this are dropped. This means the capacity of freed pages is lost, including the old
+/// page when grow_page or shrink_page copies a page. A [crate::local_alloc::LocalAlloc] on top of this should be
+/// configured with `free_after(usize::MAX)`, so it keeps its empty pages instead of freeing them and allocating
+/// new ones.
+/// A root pointer can be stored in the file using [FilePageAlloc::set_root] to find the data after reopening.
+pub struct FilePageAlloc {
+    _file: File,
+    base: Ptr,
+    capacity: usize,
+    // Guards the header, which lives inside the mapping
+    lock: Mutex<()>,
+}
+
+impl FilePageAlloc {
+    /// Creates a new file at the configured path, the file is replaced if it already exists.
+    ///
+    /// The new file is set up next to the old one and only replaces it once it is mapped,
+    /// so the old file is kept if this fails.
+    pub fn create(config: Config) -> io::Result<Self> {
+        let capacity = config.capacity.next_multiple_of(1 << 12);
+        if capacity <= HEADER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "capacity has to be bigger than 4KB",
+            ));
+        }
+
+        let temp_path = temp_path(&config.path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        let base = match file
+            .set_len(capacity as u64)
+            .and_then(|_| map_file(&file, capacity, config.base_addr))
+        {
+            Ok(base) => base,
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        };
+        let header = Header {
+            magic: MAGIC,
+            base_addr: base as u64,
+            capacity: capacity as u64,
+            used: 0,
+            root: 0,
+        };
+        // Safety: header is at the start of the mapping we just created
+        unsafe { (base as *mut Header).write(header) };
+
+        // unmaps the file on error
+        let page_alloc = Self {
+            _file: file,
+            base,
+            capacity,
+            lock: Mutex::new(()),
+        };
+        if let Err(e) = std::fs::rename(&temp_path, &config.path) {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        Ok(page_alloc)
+    }
+
+    /// Opens a file that was created by [FilePageAlloc::create] and maps it at the address it was created at.
+    ///
+    /// Fails if something else is mapped in that address range.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        let mut buf = [0u8; std::mem::size_of::<Header>()];
+        file.read_exact_at(&mut buf, 0)?;
+        // Safety: Header is plain old data and buf has the same size
+        let header = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const Header) };
+        if header.magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file wasn't created by FilePageAlloc",
+            ));
+        }
+        let capacity = header.capacity as usize;
+        if file.metadata()?.len() < header.capacity {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file is smaller than its recorded capacity",
+            ));
+        }
+
+        let base = map_file(&file, capacity, Some(header.base_addr as usize))?;
+
+        Ok(Self {
+            _file: file,
+            base,
+            capacity,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Address the file is mapped at.
+    pub fn base_addr(&self) -> usize {
+        self.base
+    }
+
+    /// Stores a pointer in the file header so it can be retrieved after reopening the file.
+    pub fn set_root(&self, root: Option<NonNull<u8>>) {
+        let _guard = self.lock.lock().unwrap();
+        let root = root.map(|x| x.as_ptr() as u64).unwrap_or(0);
+        // Safety: header is at the start of our mapping and we hold the lock
+        unsafe { (*(self.base as *mut Header)).root = root };
+    }
+
+    pub fn root(&self) -> Option<NonNull<u8>> {
+        let _guard = self.lock.lock().unwrap();
+        // Safety: header is at the start of our mapping and we hold the lock
+        let root = unsafe { (*(self.base as *const Header)).root };
+        NonNull::new(root as *mut u8)
+    }
+
+    /// Writes the changes to the file.
+    pub fn sync(&self) -> io::Result<()> {
+        // Safety: we own the mapping
+        match unsafe { libc::msync(self.base as *mut libc::c_void, self.capacity, libc::MS_SYNC) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+impl Drop for FilePageAlloc {
+    fn drop(&mut self) {
+        // Safety: we own the mapping and it can't be referenced after drop.
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.capacity) };
+    }
+}
+
+// Safety: moving the struct doesn't invalidate currently allocated pages
+unsafe impl PageAlloc for FilePageAlloc {
+    fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc_page_aligned(size, 1 << 12)
+    }
+
+    fn zeroes_pages(&self) -> bool {
+        // pages are never reused and the file is zeroed when it is created
+        true
+    }
+
+    fn alloc_page_aligned(&self, size: usize, align: usize) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(size > 0);
+        assert!(align.is_power_of_two());
+
+        let _guard = self.lock.lock().unwrap();
+        let header = self.base as *mut Header;
+
+        // Safety: header is at the start of our mapping and we hold the lock
+        let used = unsafe { (*header).used } as usize;
+        let start = align_up(self.base + HEADER_SIZE + used, align.max(1 << 12));
+        let len = size.next_multiple_of(1 << 12);
+        if start + len > self.base + self.capacity {
+            return Err(AllocError);
+        }
+        // Safety: header is at the start of our mapping and we hold the lock
+        unsafe { (*header).used = (start + len - self.base - HEADER_SIZE) as u64 };
+
+        let ptr = NonNull::new(start as *mut u8).unwrap();
+        Ok(NonNull::slice_from_raw_parts(ptr, len))
+    }
+
+    unsafe fn dealloc_page(&self, _page: NonNull<[u8]>) {}
+}
+
+/// Path of the file that create sets up before it replaces the file at path.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.tmp", std::process::id()));
+    path.with_file_name(name)
+}
+
+fn map_file(file: &File, len: usize, addr: Option<usize>) -> io::Result<Ptr> {
+    let (hint, flags) = match addr {
+        Some(addr) => (addr, libc::MAP_SHARED | libc::MAP_FIXED_NOREPLACE),
+        None => (0, libc::MAP_SHARED),
+    };
+
+    // Safety: Call format fits mmap manpage. MAP_FIXED_NOREPLACE never replaces existing mappings.
+    let ptr = unsafe {
+        libc::mmap(
+            hint as *mut libc::c_void,
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Kernels older than 4.17 treat MAP_FIXED_NOREPLACE as a hint
+    if let Some(addr) = addr {
+        if ptr as usize != addr {
+            // Safety: we just created this mapping
+            unsafe { libc::munmap(ptr, len) };
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+    }
+
+    Ok(ptr as usize)
+}
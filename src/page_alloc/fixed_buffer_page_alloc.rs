@@ -0,0 +1,123 @@
+use core::alloc::AllocError;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+use std::sync::Mutex;
+
+use super::PageAlloc;
+use crate::util::{align_down, align_offset, align_up};
+
+// Use this to avoid creating aliased pointers.
+type Ptr = usize;
+
+#[derive(Clone, Copy)]
+struct Slice {
+    ptr: Ptr,
+    len: usize,
+}
+
+/// Page allocator that allocates pages from a buffer given by the user, it never calls into the OS.
+///
+/// The 4KB aligned part of the buffer is used for pages, so the buffer should be 4KB aligned
+/// to not waste any memory. Allocation fails when the buffer is exhausted.
+pub struct FixedBufferPageAlloc<'a> {
+    // Parts of the buffer that are not used by any page
+    free_ranges: Mutex<Vec<Slice>>,
+    _buf: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> FixedBufferPageAlloc<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        let buf_start = buf.as_mut_ptr() as usize;
+        let start = align_up(buf_start, 1 << 12);
+        let end = align_down(buf_start + buf.len(), 1 << 12);
+
+        let mut free_ranges = Vec::new();
+        if start < end {
+            free_ranges.push(Slice {
+                ptr: start,
+                len: end - start,
+            });
+        }
+
+        Self {
+            free_ranges: Mutex::new(free_ranges),
+            _buf: PhantomData,
+        }
+    }
+
+    /// Total size of the parts of the buffer that are not used by any page.
+    pub fn free_bytes(&self) -> usize {
+        self.free_ranges
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|range| range.len)
+            .sum()
+    }
+}
+
+// Safety: pages live in the user buffer, which isn't moved together with the struct.
+unsafe impl PageAlloc for FixedBufferPageAlloc<'_> {
+    fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc_page_aligned(size, 1 << 12)
+    }
+
+    fn alloc_page_aligned(&self, size: usize, align: usize) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(size > 0);
+        assert!(align.is_power_of_two());
+
+        let align = align.max(1 << 12);
+        let len = size.next_multiple_of(1 << 12);
+
+        let mut free_ranges = self.free_ranges.lock().unwrap();
+        let idx = free_ranges
+            .iter()
+            .position(|range| range.len >= align_offset(range.ptr, align) + len)
+            .ok_or(AllocError)?;
+
+        let range = free_ranges.swap_remove(idx);
+        let alignment_offset = align_offset(range.ptr, align);
+        if alignment_offset > 0 {
+            free_ranges.push(Slice {
+                ptr: range.ptr,
+                len: alignment_offset,
+            });
+        }
+        if range.len > alignment_offset + len {
+            free_ranges.push(Slice {
+                ptr: range.ptr + alignment_offset + len,
+                len: range.len - alignment_offset - len,
+            });
+        }
+
+        let ptr = NonNull::new((range.ptr + alignment_offset) as *mut u8).unwrap();
+        Ok(NonNull::slice_from_raw_parts(ptr, len))
+    }
+
+    unsafe fn dealloc_page(&self, page: NonNull<[u8]>) {
+        let mut range = Slice {
+            ptr: page.cast::<u8>().as_ptr() as usize,
+            len: page.len(),
+        };
+
+        let mut free_ranges = self.free_ranges.lock().unwrap();
+        // this loop pattern is used because we need to remove items while iterating
+        let mut idx = 0;
+        while idx < free_ranges.len() {
+            let other = *free_ranges.get(idx).unwrap();
+            if other.ptr + other.len == range.ptr {
+                range = Slice {
+                    ptr: other.ptr,
+                    len: other.len + range.len,
+                };
+                free_ranges.swap_remove(idx);
+            } else if range.ptr + range.len == other.ptr {
+                range.len += other.len;
+                free_ranges.swap_remove(idx);
+            } else {
+                idx += 1;
+            }
+        }
+        free_ranges.push(range);
+    }
+}
@@ -0,0 +1,176 @@
+use core::alloc::AllocError;
+use core::ptr::NonNull;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+
+use super::PageAlloc;
+use crate::util::{align_down, align_up};
+
+// Use this to avoid creating aliased pointers.
+type Ptr = usize;
+
+#[derive(Clone, Copy)]
+struct Slice {
+    ptr: Ptr,
+    len: usize,
+}
+
+impl Slice {
+    fn to_page(self) -> NonNull<[u8]> {
+        let ptr = NonNull::new(self.ptr as *mut u8).unwrap();
+        NonNull::slice_from_raw_parts(ptr, self.len)
+    }
+}
+
+pub struct Config<P: PageAlloc> {
+    page_alloc: P,
+    guard_size: usize,
+}
+
+impl<P: PageAlloc> Config<P> {
+    pub fn new(page_alloc: P) -> Self {
+        Self {
+            page_alloc,
+            guard_size: 1 << 12, // 4 KB
+        }
+    }
+
+    /// Size of the guard regions on each side of a page, rounded up to a multiple of 4KB.
+    pub fn guard_size(&mut self, guard_size: usize) -> &mut Self {
+        self.guard_size = guard_size;
+        self
+    }
+}
+
+/// Page allocator that surrounds every page with inaccessible guard regions,
+/// so reading or writing past either end of a page crashes the program immediately.
+///
+/// Guard regions are carved out of pages allocated from the underlying page allocator using mprotect.
+pub struct GuardedPageAlloc<P: PageAlloc> {
+    page_alloc: P,
+    guard_size: usize,
+    // Pages allocated from the underlying page allocator including the guard regions, start address -> length
+    inner_pages: Mutex<BTreeMap<Ptr, usize>>,
+}
+
+impl<P: PageAlloc> GuardedPageAlloc<P> {
+    pub fn new(config: Config<P>) -> Self {
+        Self {
+            page_alloc: config.page_alloc,
+            guard_size: config.guard_size.next_multiple_of(1 << 12).max(1 << 12),
+            inner_pages: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn protect_guards(inner_page: Slice, page: Slice, prot: libc::c_int) -> io::Result<()> {
+        let page_end = page.ptr + page.len;
+        // Safety: guard regions are 4KB aligned parts of a page that is allocated by us and nothing references them.
+        unsafe {
+            mprotect_wrapper(inner_page.ptr, page.ptr - inner_page.ptr, prot)?;
+            mprotect_wrapper(page_end, inner_page.ptr + inner_page.len - page_end, prot)
+        }
+    }
+}
+
+// Safety: pages are owned by the underlying page allocator, which is required to not invalidate them on move.
+unsafe impl<P: PageAlloc> PageAlloc for GuardedPageAlloc<P> {
+    fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc_page_aligned(size, 1 << 12)
+    }
+
+    fn zeroes_pages(&self) -> bool {
+        self.page_alloc.zeroes_pages()
+    }
+
+    fn alloc_page_aligned(&self, size: usize, align: usize) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(size > 0);
+        assert!(align.is_power_of_two());
+
+        // front guard is extended so the page start stays aligned
+        let front_guard_size = align_up(self.guard_size, align);
+        let inner_size = front_guard_size + size.next_multiple_of(1 << 12) + self.guard_size;
+        let inner_page = self.page_alloc.alloc_page_aligned(inner_size, align)?;
+        let inner_page = Slice {
+            ptr: inner_page.cast::<u8>().as_ptr() as usize,
+            len: inner_page.len(),
+        };
+
+        // Underlying page allocator might return a bigger page than requested, so place the
+        // back guard at the end of the page it returned.
+        let page_ptr = inner_page.ptr + front_guard_size;
+        let page_end = align_down(inner_page.ptr + inner_page.len - self.guard_size, 1 << 12);
+        let page = Slice {
+            ptr: page_ptr,
+            len: page_end - page_ptr,
+        };
+
+        if Self::protect_guards(inner_page, page, libc::PROT_NONE).is_err() {
+            // Safety: inner_page is allocated from page_alloc and nothing references it.
+            unsafe {
+                let _ = Self::protect_guards(inner_page, page, libc::PROT_READ | libc::PROT_WRITE);
+                self.page_alloc.dealloc_page(inner_page.to_page());
+            }
+            return Err(AllocError);
+        }
+
+        self.inner_pages
+            .lock()
+            .unwrap()
+            .insert(inner_page.ptr, inner_page.len);
+
+        Ok(page.to_page())
+    }
+
+    unsafe fn dealloc_page(&self, page: NonNull<[u8]>) {
+        let page = Slice {
+            ptr: page.cast::<u8>().as_ptr() as usize,
+            len: page.len(),
+        };
+
+        let inner_page = {
+            let mut inner_pages = self.inner_pages.lock().unwrap();
+            // the front guard is before the page, so the inner page starts before it
+            let (&ptr, &len) = inner_pages
+                .range(..page.ptr)
+                .next_back()
+                .filter(|(&ptr, &len)| ptr + len > page.ptr)
+                .expect("find guarded page");
+            inner_pages.remove(&ptr);
+            Slice { ptr, len }
+        };
+
+        // Underlying page allocator might reuse this memory so the guard regions have to be accessible.
+        // If this fails the guard regions stay inaccessible, which only means we leak them.
+        if Self::protect_guards(inner_page, page, libc::PROT_READ | libc::PROT_WRITE).is_ok() {
+            self.page_alloc.dealloc_page(inner_page.to_page());
+        }
+    }
+
+    fn decommits(&self) -> bool {
+        self.page_alloc.decommits()
+    }
+
+    unsafe fn decommit(&self, range: NonNull<[u8]>) {
+        self.page_alloc.decommit(range);
+    }
+
+    unsafe fn recommit(&self, range: NonNull<[u8]>) {
+        self.page_alloc.recommit(range);
+    }
+
+    unsafe fn prefault(&self, range: NonNull<[u8]>) {
+        self.page_alloc.prefault(range);
+    }
+}
+
+unsafe fn mprotect_wrapper(ptr: Ptr, len: usize, prot: libc::c_int) -> io::Result<()> {
+    match libc::mprotect(ptr as *mut libc::c_void, len, prot) {
+        0 => Ok(()),
+        -1 => Err(io::Error::last_os_error()),
+        x => Err(io::Error::other(format!(
+            "unexpected return value from mprotect: {}. Expected 0 or -1",
+            x
+        ))),
+    }
+}
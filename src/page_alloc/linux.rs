@@ -1,55 +1,456 @@
 use std::alloc::AllocError;
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
 use std::io;
 use std::ptr::NonNull;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 
-use super::PageAlloc;
+use super::{realloc_page_by_copy, PageAlloc};
+use crate::util::{align_down, align_up};
 
-pub struct DynamicPageAlloc;
+// Use this to avoid creating aliased pointers.
+type Ptr = usize;
+
+#[derive(Clone, Copy)]
+struct Slice {
+    ptr: Ptr,
+    len: usize,
+}
+
+impl Slice {
+    fn from_page(page: NonNull<[u8]>) -> Self {
+        Self {
+            ptr: page.cast::<u8>().as_ptr() as usize,
+            len: page.len(),
+        }
+    }
+
+    fn to_page(self) -> NonNull<[u8]> {
+        let ptr = NonNull::new(self.ptr as *mut u8).unwrap();
+        NonNull::slice_from_raw_parts(ptr, self.len)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugeTlbSize {
+    Size2MB,
+    Size1GB,
+}
+
+impl HugeTlbSize {
+    fn bytes(self) -> usize {
+        match self {
+            Self::Size2MB => 1 << 21,
+            Self::Size1GB => 1 << 30,
+        }
+    }
+
+    fn mmap_flags(self) -> libc::c_int {
+        match self {
+            Self::Size2MB => libc::MAP_HUGETLB | libc::MAP_HUGE_2MB,
+            Self::Size1GB => libc::MAP_HUGETLB | libc::MAP_HUGE_1GB,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePagePolicy {
+    /// Use normal pages. Transparent huge pages are disabled for the pages with madvise,
+    /// so this also holds if they are enabled system wide.
+    None,
+    /// Request transparent huge pages with madvise.
+    /// This is best-effort, it is ignored if transparent huge pages are disabled on the system.
+    TransparentAdvise,
+    /// Map pages with MAP_HUGETLB, allocation fails if there are no huge pages available.
+    HugeTlb(HugeTlbSize),
+    /// Try to map pages with MAP_HUGETLB and fall back to [HugePagePolicy::TransparentAdvise] if it fails.
+    HugeTlbOrFallback(HugeTlbSize),
+}
+
+/// Controls when the memory of a newly mapped page is faulted in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitStrategy {
+    /// Fault in the whole page when it is mapped.
+    ///
+    /// This uses MADV_POPULATE_WRITE after the NUMA policy and the page attributes are applied instead of
+    /// MAP_POPULATE, so memory is placed according to the policy and only the aligned part of an
+    /// over-reserved mapping is faulted in. Pages are touched manually on kernels older than 5.14.
+    Eager,
+    /// Fault in memory on first touch.
+    Lazy,
+    /// Fault in the first N bytes of the page when it is mapped and the rest on first touch.
+    PopulatePrefix(usize),
+    /// Return the page immediately and fault it in on a background thread.
+    ///
+    /// The thread is started on first use and it is stopped when the allocator is dropped.
+    /// This requires MADV_POPULATE_WRITE which is supported since Linux 5.14,
+    /// memory is faulted in on first touch on older kernels.
+    Background,
+}
+
+/// Controls how decommitted memory is given back to the OS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecommitStrategy {
+    /// Free the memory immediately using MADV_DONTNEED.
+    DontNeed,
+    /// Use MADV_FREE, which lets the kernel free the memory lazily when it is under memory pressure.
+    /// This is cheaper but resident memory usage doesn't go down until the memory is actually freed.
+    Free,
+}
+
+/// Controls which NUMA nodes the memory of the pages is placed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumaPolicy {
+    /// Use the memory policy of the thread that faults in the memory.
+    Default,
+    /// Place memory on the given node.
+    Bind(usize),
+    /// Interleave memory across the nodes in the given bit mask, bit N corresponds to node N.
+    Interleave(u64),
+    /// Place memory on the node of the CPU that faults it in.
+    Local,
+}
+
+/// Attributes that are applied to every page the allocator maps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageAttributes {
+    /// Lock the pages in memory with mlock, so they are never swapped out.
+    ///
+    /// Locked memory can't be decommitted.
+    pub lock: bool,
+    /// Exclude the pages from core dumps using MADV_DONTDUMP.
+    pub dont_dump: bool,
+    /// Don't map the pages into child processes created with fork using MADV_DONTFORK.
+    pub dont_fork: bool,
+    /// Map the pages as zeroed memory in child processes created with fork using MADV_WIPEONFORK.
+    ///
+    /// This isn't supported for hugetlb pages.
+    pub wipe_on_fork: bool,
+}
+
+pub struct Config {
+    huge_pages: HugePagePolicy,
+    commit: CommitStrategy,
+    decommit: DecommitStrategy,
+    numa: NumaPolicy,
+    attributes: PageAttributes,
+    name: Option<CString>,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            huge_pages: HugePagePolicy::TransparentAdvise,
+            commit: CommitStrategy::Eager,
+            decommit: DecommitStrategy::DontNeed,
+            numa: NumaPolicy::Default,
+            attributes: PageAttributes::default(),
+            name: None,
+        }
+    }
+
+    pub fn huge_pages(&mut self, huge_pages: HugePagePolicy) -> &mut Self {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    pub fn commit(&mut self, commit: CommitStrategy) -> &mut Self {
+        self.commit = commit;
+        self
+    }
+
+    pub fn decommit(&mut self, decommit: DecommitStrategy) -> &mut Self {
+        self.decommit = decommit;
+        self
+    }
+
+    pub fn numa(&mut self, numa: NumaPolicy) -> &mut Self {
+        self.numa = numa;
+        self
+    }
+
+    pub fn attributes(&mut self, attributes: PageAttributes) -> &mut Self {
+        self.attributes = attributes;
+        self
+    }
+
+    /// Name that the mapped pages show up with in /proc/self/maps, as `[anon:<name>]`.
+    /// Naming is skipped if the kernel doesn't support naming anonymous mappings.
+    ///
+    /// The name can be at most 79 bytes long and it can only contain printable ascii characters
+    /// other than `[`, `]`, `\`, `$` and `` ` ``.
+    pub fn name(&mut self, name: &CStr) -> &mut Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Normal mappings are made in multiples of 2MB so they can be backed by transparent huge pages.
+const NORMAL_MAPPING_GRANULARITY: usize = 1 << 21;
+
+#[derive(Clone, Copy)]
+struct MappedPage {
+    len: usize,
+    // Size of the pages the mapping is made of, the mapping size is a multiple of this.
+    // This can differ between pages if hugetlb pages fall back to normal pages.
+    granularity: usize,
+}
+
+/// Page allocator that maps memory directly from the kernel using mmap.
+///
+/// Failures are reported as `AllocError`, the OS error that caused the most recent failure
+/// can be retrieved using [DynamicPageAlloc::last_error].
+pub struct DynamicPageAlloc {
+    huge_pages: HugePagePolicy,
+    commit: CommitStrategy,
+    decommit: DecommitStrategy,
+    numa: NumaPolicy,
+    attributes: PageAttributes,
+    name: Option<CString>,
+    last_errno: AtomicI32,
+    // Pages that are currently allocated, by start address
+    pages: Mutex<BTreeMap<Ptr, MappedPage>>,
+    // Ranges for the background prefault thread
+    prefault_queue: Arc<PrefaultQueue>,
+}
+
+impl DynamicPageAlloc {
+    pub fn new(config: Config) -> Self {
+        Self {
+            huge_pages: config.huge_pages,
+            commit: config.commit,
+            decommit: config.decommit,
+            numa: config.numa,
+            attributes: config.attributes,
+            name: config.name,
+            last_errno: AtomicI32::new(0),
+            pages: Mutex::new(BTreeMap::new()),
+            prefault_queue: Arc::new(PrefaultQueue::default()),
+        }
+    }
+
+    /// Returns the OS error that caused the most recent failure of this allocator.
+    ///
+    /// The allocator might be shared between threads so the error might come from a call made by another thread.
+    pub fn last_error(&self) -> Option<io::Error> {
+        match self.last_errno.load(Ordering::Relaxed) {
+            0 => None,
+            errno => Some(io::Error::from_raw_os_error(errno)),
+        }
+    }
+
+    /// Returns the NUMA node the memory at the start of page is placed on.
+    ///
+    /// The memory is faulted in if it isn't already. Returns 0 if NUMA isn't available, see [numa_unavailable].
+    pub fn page_node(&self, page: NonNull<[u8]>) -> io::Result<usize> {
+        let mut node: libc::c_int = 0;
+        // Safety: Call format fits get_mempolicy manpage, node is the only thing written to.
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_get_mempolicy,
+                &mut node as *mut libc::c_int,
+                std::ptr::null_mut::<libc::c_ulong>(),
+                0 as libc::c_ulong,
+                page.cast::<u8>().as_ptr() as *mut libc::c_void,
+                (MPOL_F_NODE | MPOL_F_ADDR) as libc::c_ulong,
+            )
+        };
+        match res {
+            0 => Ok(node as usize),
+            _ => {
+                let err = io::Error::last_os_error();
+                if numa_unavailable(&err) {
+                    Ok(0)
+                } else {
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    /// Returns the residency of every page that is currently allocated from this allocator, see [residency].
+    pub fn residency(&self) -> io::Result<Vec<(NonNull<[u8]>, Residency)>> {
+        let pages = self
+            .pages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&ptr, page)| Slice { ptr, len: page.len }.to_page())
+            .collect::<Vec<_>>();
+        let residency = residency(&pages)?;
+        Ok(pages.into_iter().zip(residency).collect())
+    }
+
+    fn prefault_in_background(&self, range: NonNull<[u8]>) {
+        let mut state = self.prefault_queue.state.lock().unwrap();
+        if !state.started {
+            match spawn_prefault_thread(self.prefault_queue.clone()) {
+                Ok(()) => state.started = true,
+                // memory is faulted in on first touch instead
+                Err(e) => {
+                    self.record_error(e);
+                    return;
+                }
+            }
+        }
+        let range = Slice::from_page(range);
+        state.queued.insert(range.ptr, range.len);
+        self.prefault_queue.changed.notify_all();
+    }
+
+    /// Removes range from the ranges queued for the background prefault thread and waits until the thread
+    /// isn't faulting in any part of it. This has to be called before range is unmapped or moved.
+    fn cancel_prefault(&self, range: NonNull<[u8]>) {
+        if self.commit != CommitStrategy::Background {
+            return;
+        }
+
+        let start = range.cast::<u8>().as_ptr() as usize;
+        let end = start + range.len();
+        let overlaps = |x: Slice| x.ptr < end && start < x.ptr + x.len;
+
+        let mut state = self.prefault_queue.state.lock().unwrap();
+        let overlapping = state
+            .queued
+            .range(..end)
+            .rev()
+            .map(|(&ptr, &len)| Slice { ptr, len })
+            .take_while(|&x| overlaps(x))
+            .collect::<Vec<_>>();
+        for x in overlapping {
+            state.queued.remove(&x.ptr);
+            if x.ptr < start {
+                state.queued.insert(x.ptr, start - x.ptr);
+            }
+            if x.ptr + x.len > end {
+                state.queued.insert(end, x.ptr + x.len - end);
+            }
+        }
+        while state.in_progress.is_some_and(overlaps) {
+            state = self.prefault_queue.changed.wait(state).unwrap();
+        }
+    }
+
+    fn add_page(&self, page: NonNull<[u8]>, granularity: usize) {
+        let page = Slice::from_page(page);
+        self.pages.lock().unwrap().insert(
+            page.ptr,
+            MappedPage {
+                len: page.len,
+                granularity,
+            },
+        );
+    }
+
+    fn remove_page(&self, page: NonNull<[u8]>) {
+        let ptr = page.cast::<u8>().as_ptr() as usize;
+        self.pages.lock().unwrap().remove(&ptr);
+    }
+
+    /// Returns the granularity of the mapping of a currently allocated page.
+    fn granularity(&self, page: NonNull<[u8]>) -> usize {
+        let ptr = page.cast::<u8>().as_ptr() as usize;
+        self.pages
+            .lock()
+            .unwrap()
+            .get(&ptr)
+            .expect("find page")
+            .granularity
+    }
+
+    fn apply_name(&self, page: NonNull<[u8]>) {
+        if let Some(name) = &self.name {
+            // Naming is only for diagnostics so failing to do it isn't an error.
+            let _ = name_mapping(page, name);
+        }
+    }
+
+    fn record_error(&self, err: io::Error) -> AllocError {
+        let errno = err.raw_os_error().unwrap_or(libc::EINVAL);
+        self.last_errno.store(errno, Ordering::Relaxed);
+        AllocError
+    }
+}
+
+impl Drop for DynamicPageAlloc {
+    fn drop(&mut self) {
+        let mut state = self.prefault_queue.state.lock().unwrap();
+        state.stopped = true;
+        state.queued.clear();
+        self.prefault_queue.changed.notify_all();
+    }
+}
+
+impl Default for DynamicPageAlloc {
+    fn default() -> Self {
+        Self::new(Config::new())
+    }
+}
 
 // Safety: moving the struct doesn't invalidate currently allocated pages
 unsafe impl PageAlloc for DynamicPageAlloc {
     fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc_page_aligned(size, 1 << 12)
+    }
+
+    fn zeroes_pages(&self) -> bool {
+        true
+    }
+
+    fn alloc_page_aligned(&self, size: usize, align: usize) -> Result<NonNull<[u8]>, AllocError> {
         assert!(size > 0);
+        assert!(align.is_power_of_two());
+
+        let map_normal = |advice| {
+            map_normal_pages(size, align, advice).map(|page| (page, NORMAL_MAPPING_GRANULARITY))
+        };
+        let map_huge_tlb = |page_size: HugeTlbSize| {
+            map_huge_tlb_pages(size, align, page_size).map(|page| (page, page_size.bytes()))
+        };
+        let (page, granularity) = match self.huge_pages {
+            HugePagePolicy::None => map_normal(libc::MADV_NOHUGEPAGE),
+            HugePagePolicy::TransparentAdvise => map_normal(libc::MADV_HUGEPAGE),
+            HugePagePolicy::HugeTlb(page_size) => map_huge_tlb(page_size),
+            HugePagePolicy::HugeTlbOrFallback(page_size) => {
+                map_huge_tlb(page_size).or_else(|_| map_normal(libc::MADV_HUGEPAGE))
+            }
+        }
+        .map_err(|e| self.record_error(e))?;
 
-        let alloc_size = size.next_multiple_of(1 << 21); // round up to next multiple of 2MB
+        // Safety: page was just mapped by us and nothing references it yet
+        if let Err(e) = unsafe {
+            mbind_wrapper(page, self.numa).and_then(|_| apply_attributes(page, self.attributes))
+        } {
+            // Safety: page was just mapped by us and nothing references it yet
+            unsafe { self.dealloc_page(page) };
+            return Err(self.record_error(e));
+        }
 
-        let page = match mmap_wrapper(alloc_size) {
-            Ok(page) => page,
-            Err(e) => {
-                eprintln!("failed to allocate memory with mmap: {}.\naborting.", e);
-                std::process::abort();
+        let populate_len = match self.commit {
+            CommitStrategy::Eager => page.len(),
+            CommitStrategy::Lazy | CommitStrategy::Background => 0,
+            CommitStrategy::PopulatePrefix(prefix_len) => {
+                prefix_len.next_multiple_of(1 << 12).min(page.len())
             }
         };
+        if populate_len > 0 {
+            let range = NonNull::slice_from_raw_parts(page.cast::<u8>(), populate_len);
+            // Safety: range is inside the page we just mapped and nothing references it yet
+            unsafe { populate(range) };
+        }
 
-        // Safety: we know page is valid allocated memory that has more than 0 size.
-        // there is nothing indicating this can cause UB in man page of madvise
-        unsafe {
-            match libc::madvise(
-                page.cast::<u8>().as_ptr() as *mut libc::c_void,
-                page.len(),
-                libc::MADV_HUGEPAGE,
-            ) {
-                0 => (),
-                -1 => {
-                    let errno = *libc::__errno_location();
-                    let err = std::io::Error::from_raw_os_error(errno);
-
-                    // TODO: maybe shouldn't abort here :)
-                    eprintln!(
-                        "failed to call madvise on allocated memory: {}.\naborting",
-                        err
-                    );
-                    std::process::abort();
-                }
-                x => {
-                    // TODO: maybe shouldn't abort here :)
-                    eprintln!(
-                        "unexpected return value from madvise: {}. Expected 0 or -1\naborting",
-                        x
-                    );
-                    std::process::abort();
-                }
-            }
+        self.apply_name(page);
+        self.add_page(page, granularity);
+
+        if self.commit == CommitStrategy::Background {
+            self.prefault_in_background(page);
         }
 
         Ok(page)
@@ -58,15 +459,478 @@ unsafe impl PageAlloc for DynamicPageAlloc {
     unsafe fn dealloc_page(&self, page: NonNull<[u8]>) {
         let ptr = page.cast::<u8>().as_ptr();
         let size = page.len();
+        self.cancel_prefault(page);
+
+        // munmap can only fail if the page wasn't allocated by us, which means the caller broke the contract.
+        // The mapping is left as is in this case and the error can be retrieved using last_error.
+        match munmap_wrapper(ptr, size) {
+            Ok(()) => self.remove_page(page),
+            Err(e) => {
+                self.record_error(e);
+            }
+        }
+    }
+
+    unsafe fn grow_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(new_size > page.len());
 
-        if let Err(e) = munmap_wrapper(ptr, size) {
-            eprintln!("failed to deallocate page with munmap: {}\naborting.", e);
-            std::process::abort();
+        // mremap keeps the page attributes, since they are properties of the mapping.
+        let granularity = self.granularity(page);
+        let new_len = new_size.next_multiple_of(granularity);
+        // mremap can move the page, so the old address range can't be faulted in anymore.
+        self.cancel_prefault(page);
+        match mremap_wrapper(page, new_len) {
+            Ok(new_page) => {
+                let grown_ptr = new_page.cast::<u8>().add(page.len());
+                let grown = NonNull::slice_from_raw_parts(grown_ptr, new_len - page.len());
+                match self.commit {
+                    // Safety: grown part of the page was just mapped and nothing references it yet
+                    CommitStrategy::Eager => populate(grown),
+                    // The old part might not be faulted in completely since it was cancelled
+                    CommitStrategy::Background => self.prefault_in_background(new_page),
+                    CommitStrategy::Lazy | CommitStrategy::PopulatePrefix(_) => (),
+                }
+                self.apply_name(new_page);
+                self.remove_page(page);
+                self.add_page(new_page, granularity);
+                Ok(new_page)
+            }
+            // mremap can't resize some mappings, for example hugetlb mappings on older kernels.
+            Err(_) => realloc_page_by_copy(self, page, new_size),
+        }
+    }
+
+    unsafe fn shrink_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(new_size < page.len());
+        assert!(new_size > 0);
+
+        let granularity = self.granularity(page);
+        let new_len = new_size.next_multiple_of(granularity);
+        if new_len >= page.len() {
+            return Ok(page);
+        }
+
+        let tail = page.cast::<u8>().as_ptr().add(new_len);
+        self.cancel_prefault(NonNull::slice_from_raw_parts(
+            NonNull::new(tail).unwrap(),
+            page.len() - new_len,
+        ));
+        munmap_wrapper(tail, page.len() - new_len).map_err(|e| self.record_error(e))?;
+
+        let new_page = NonNull::slice_from_raw_parts(page.cast::<u8>(), new_len);
+        self.remove_page(page);
+        self.add_page(new_page, granularity);
+        Ok(new_page)
+    }
+
+    unsafe fn prefault(&self, range: NonNull<[u8]>) {
+        populate(range);
+    }
+
+    fn decommits(&self) -> bool {
+        true
+    }
+
+    unsafe fn decommit(&self, range: NonNull<[u8]>) {
+        let advice = match self.decommit {
+            DecommitStrategy::DontNeed => libc::MADV_DONTNEED,
+            DecommitStrategy::Free => libc::MADV_FREE,
+        };
+        // Otherwise the background thread could fault the memory in again right after it is decommitted
+        self.cancel_prefault(range);
+
+        // Decommitting is only a hint, the memory stays usable if it fails.
+        if let Err(e) = madvise_wrapper(range, advice) {
+            self.record_error(e);
+        }
+    }
+}
+
+/// Maps normal pages and applies huge_page_advice to them, which is MADV_HUGEPAGE or MADV_NOHUGEPAGE.
+fn map_normal_pages(
+    size: usize,
+    align: usize,
+    huge_page_advice: libc::c_int,
+) -> io::Result<NonNull<[u8]>> {
+    let alloc_size = size.next_multiple_of(NORMAL_MAPPING_GRANULARITY);
+
+    let page = mmap_aligned(alloc_size, align, 1 << 12, 0)?;
+
+    // Safety: we know page is valid allocated memory that has more than 0 size.
+    // there is nothing indicating this can cause UB in man page of madvise
+    match unsafe { madvise_wrapper(page, huge_page_advice) } {
+        Ok(()) => (),
+        // EINVAL means transparent huge pages are not supported by the kernel
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => (),
+        Err(e) => {
+            // Safety: page was just mapped by us and nothing references it yet
+            unsafe { munmap_wrapper(page.cast::<u8>().as_ptr(), page.len())? };
+            return Err(e);
+        }
+    }
+
+    Ok(page)
+}
+
+fn map_huge_tlb_pages(
+    size: usize,
+    align: usize,
+    page_size: HugeTlbSize,
+) -> io::Result<NonNull<[u8]>> {
+    // size of a hugetlb mapping has to be a multiple of the huge page size
+    let alloc_size = size.next_multiple_of(page_size.bytes());
+
+    mmap_aligned(alloc_size, align, page_size.bytes(), page_size.mmap_flags())
+}
+
+/// Maps size bytes aligned to align.
+///
+/// mmap only guarantees the alignment of the underlying page size, so bigger alignments are
+/// handled by over-reserving and then unmapping the unaligned head and the excess tail.
+fn mmap_aligned(
+    size: usize,
+    align: usize,
+    page_size: usize,
+    extra_flags: libc::c_int,
+) -> io::Result<NonNull<[u8]>> {
+    if align <= page_size {
+        return mmap_wrapper(size, extra_flags);
+    }
+
+    let reserved = mmap_wrapper(size + align - page_size, extra_flags)?;
+    let reserved_start = reserved.cast::<u8>().as_ptr() as usize;
+    let reserved_end = reserved_start + reserved.len();
+    let start = align_up(reserved_start, align);
+    let end = start + size;
+
+    // Safety: head and tail are parts of the mapping we just created and nothing references them.
+    // Their sizes are multiples of page_size since reserved_start, align and size are.
+    // If trimming fails, the part of the reservation that is still mapped is unmapped so it doesn't leak.
+    unsafe {
+        if start > reserved_start {
+            if let Err(e) = munmap_wrapper(reserved_start as *mut u8, start - reserved_start) {
+                let _ = munmap_wrapper(reserved_start as *mut u8, reserved.len());
+                return Err(e);
+            }
+        }
+        if reserved_end > end {
+            if let Err(e) = munmap_wrapper(end as *mut u8, reserved_end - end) {
+                let _ = munmap_wrapper(start as *mut u8, reserved_end - start);
+                return Err(e);
+            }
+        }
+    }
+
+    let ptr = NonNull::new(start as *mut u8).unwrap();
+    Ok(NonNull::slice_from_raw_parts(ptr, size))
+}
+
+// These are not defined in libc
+const MPOL_F_NODE: libc::c_int = 1;
+const MPOL_F_ADDR: libc::c_int = 2;
+
+/// Returns true if err means NUMA policies can't be used, so the memory gets no policy.
+///
+/// ENOSYS is returned by kernels built without NUMA and EPERM when the syscalls are blocked (e.g. by seccomp
+/// in containers). EINVAL, which is returned for nodes that don't exist, is a real error.
+fn numa_unavailable(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENOSYS | libc::EPERM))
+}
+
+/// Applies the NUMA policy to the range. It is a no-op if NUMA isn't available, see [numa_unavailable].
+unsafe fn mbind_wrapper(range: NonNull<[u8]>, policy: NumaPolicy) -> io::Result<()> {
+    let (mode, node_mask) = match policy {
+        NumaPolicy::Default => return Ok(()),
+        NumaPolicy::Bind(node) => {
+            if node >= 64 {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            (libc::MPOL_BIND, 1u64 << node)
+        }
+        NumaPolicy::Interleave(node_mask) => (libc::MPOL_INTERLEAVE, node_mask),
+        NumaPolicy::Local => (libc::MPOL_LOCAL, 0),
+    };
+
+    // MPOL_LOCAL requires an empty node mask
+    let (mask_ptr, max_node) = if node_mask == 0 {
+        (std::ptr::null(), 0)
+    } else {
+        (&node_mask as *const u64, 65)
+    };
+
+    match libc::syscall(
+        libc::SYS_mbind,
+        range.cast::<u8>().as_ptr() as *mut libc::c_void,
+        range.len() as libc::c_ulong,
+        mode as libc::c_ulong,
+        mask_ptr,
+        max_node as libc::c_ulong,
+        0 as libc::c_ulong,
+    ) {
+        0 => Ok(()),
+        _ => {
+            let err = io::Error::last_os_error();
+            if numa_unavailable(&err) {
+                Ok(())
+            } else {
+                Err(err)
+            }
         }
     }
 }
 
-fn mmap_wrapper(size: usize) -> io::Result<NonNull<[u8]>> {
+/// Memory usage of a range of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Residency {
+    /// Size of the range.
+    pub size: usize,
+    /// Number of bytes in the range that are resident in memory.
+    pub resident: usize,
+    /// Number of bytes in the range that are backed by huge pages,
+    /// None if it can't be determined.
+    pub huge_pages: Option<usize>,
+}
+
+impl Default for Residency {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            resident: 0,
+            huge_pages: Some(0),
+        }
+    }
+}
+
+impl std::ops::Add for Residency {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            size: self.size + other.size,
+            resident: self.resident + other.resident,
+            huge_pages: self.huge_pages.zip(other.huge_pages).map(|(a, b)| a + b),
+        }
+    }
+}
+
+impl std::iter::Sum for Residency {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |a, b| a + b)
+    }
+}
+
+/// Returns the residency of each of the given pages, pages have to be 4KB aligned.
+///
+/// Resident memory is found using mincore. Huge page usage is found by parsing /proc/self/smaps,
+/// it can only be determined if the mappings that contain huge pages are fully inside a page.
+/// This is the case for pages allocated by [DynamicPageAlloc] unless the kernel merged the mappings
+/// of adjacent pages.
+pub fn residency(pages: &[NonNull<[u8]>]) -> io::Result<Vec<Residency>> {
+    let mut result = Vec::with_capacity(pages.len());
+    for &page in pages {
+        result.push(Residency {
+            size: page.len(),
+            // Safety: mincore doesn't access the memory
+            resident: unsafe { mincore_wrapper(page)? },
+            huge_pages: Some(0),
+        });
+    }
+
+    let smaps = match std::fs::read_to_string("/proc/self/smaps") {
+        Ok(smaps) => smaps,
+        Err(_) => {
+            for residency in result.iter_mut() {
+                residency.huge_pages = None;
+            }
+            return Ok(result);
+        }
+    };
+
+    let mut mapping = (0, 0);
+    for line in smaps.lines() {
+        if let Some(range) = parse_smaps_range(line) {
+            mapping = range;
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let (Some(key), Some(value)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if !matches!(
+            key,
+            "AnonHugePages:" | "ShmemPmdMapped:" | "Shared_Hugetlb:" | "Private_Hugetlb:"
+        ) {
+            continue;
+        }
+        let bytes = match value.parse::<usize>() {
+            Ok(kb) => kb * 1024,
+            Err(_) => continue,
+        };
+        if bytes == 0 {
+            continue;
+        }
+
+        let (start, end) = mapping;
+        for (page, residency) in pages.iter().zip(result.iter_mut()) {
+            let page = Slice::from_page(*page);
+            let page_end = page.ptr + page.len;
+            if start >= page.ptr && end <= page_end {
+                residency.huge_pages = residency.huge_pages.map(|x| x + bytes);
+            } else if start < page_end && end > page.ptr {
+                // can't tell which part of the mapping the huge pages are in
+                residency.huge_pages = None;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Parses the address range from the header line of a mapping in /proc/self/smaps.
+fn parse_smaps_range(line: &str) -> Option<(usize, usize)> {
+    let (range, _) = line.split_once(' ')?;
+    let (start, end) = range.split_once('-')?;
+    let start = usize::from_str_radix(start, 16).ok()?;
+    let end = usize::from_str_radix(end, 16).ok()?;
+    Some((start, end))
+}
+
+/// Sets the name that the mappings in range show up with in /proc/self/maps.
+///
+/// Only the 4KB aligned part of range is named. It is a no-op if the kernel doesn't support
+/// naming anonymous mappings.
+fn name_mapping(range: NonNull<[u8]>, name: &CStr) -> io::Result<()> {
+    let range_start = range.cast::<u8>().as_ptr() as usize;
+    let start = align_up(range_start, 1 << 12);
+    let end = align_down(range_start + range.len(), 1 << 12);
+    if start >= end {
+        return Ok(());
+    }
+
+    // Safety: naming a mapping doesn't change its contents, name is a valid null terminated string
+    // and the kernel copies it.
+    let res = unsafe {
+        libc::prctl(
+            libc::PR_SET_VMA,
+            libc::PR_SET_VMA_ANON_NAME as libc::c_ulong,
+            start as libc::c_ulong,
+            (end - start) as libc::c_ulong,
+            name.as_ptr(),
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => {
+            let err = io::Error::last_os_error();
+            // EINVAL means the kernel is built without CONFIG_ANON_VMA_NAME or is older than 5.17
+            if err.raw_os_error() == Some(libc::EINVAL) {
+                Ok(())
+            } else {
+                Err(err)
+            }
+        }
+    }
+}
+
+unsafe fn apply_attributes(page: NonNull<[u8]>, attributes: PageAttributes) -> io::Result<()> {
+    if attributes.dont_dump {
+        madvise_wrapper(page, libc::MADV_DONTDUMP)?;
+    }
+    if attributes.dont_fork {
+        madvise_wrapper(page, libc::MADV_DONTFORK)?;
+    }
+    if attributes.wipe_on_fork {
+        madvise_wrapper(page, libc::MADV_WIPEONFORK)?;
+    }
+    if attributes.lock {
+        mlock_wrapper(page)?;
+    }
+    Ok(())
+}
+
+// The background prefault thread faults in ranges in chunks of this size, so cancelling has to wait for
+// at most one chunk.
+const PREFAULT_CHUNK_SIZE: usize = 1 << 21;
+
+#[derive(Default)]
+struct PrefaultQueue {
+    state: Mutex<PrefaultState>,
+    // Notified when a range is queued, when a chunk is done and when the allocator is dropped
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct PrefaultState {
+    started: bool,
+    stopped: bool,
+    // Ranges that still have to be faulted in, by start address
+    queued: BTreeMap<Ptr, usize>,
+    // Chunk the thread is currently faulting in
+    in_progress: Option<Slice>,
+}
+
+/// Starts a thread that faults in the ranges in queue, it stops when the allocator is dropped.
+fn spawn_prefault_thread(queue: Arc<PrefaultQueue>) -> io::Result<()> {
+    std::thread::Builder::new()
+        .name("s_alloc-prefault".to_owned())
+        .spawn(move || {
+            let mut state = queue.state.lock().unwrap();
+            while !state.stopped {
+                let Some((ptr, len)) = state.queued.pop_first() else {
+                    state = queue.changed.wait(state).unwrap();
+                    continue;
+                };
+                let chunk = Slice {
+                    ptr,
+                    len: len.min(PREFAULT_CHUNK_SIZE),
+                };
+                if len > chunk.len {
+                    state.queued.insert(ptr + chunk.len, len - chunk.len);
+                }
+                state.in_progress = Some(chunk);
+                drop(state);
+
+                // Safety: the chunk stays mapped while it is in progress, since the allocator cancels
+                // prefaulting and waits for the chunk before unmapping or moving it. Nothing else can
+                // unmap it since the page is owned by the allocator until it is deallocated.
+                // MADV_POPULATE_WRITE doesn't change the contents of the memory.
+                let _ = unsafe { madvise_wrapper(chunk.to_page(), libc::MADV_POPULATE_WRITE) };
+
+                state = queue.state.lock().unwrap();
+                state.in_progress = None;
+                queue.changed.notify_all();
+            }
+        })?;
+    Ok(())
+}
+
+/// Faults in the given range for writing.
+///
+/// # Safety
+///
+/// range has to be mapped readable and writable and it can't be accessed concurrently, since pages are
+/// written to if the kernel doesn't support MADV_POPULATE_WRITE. Contents of the range are preserved.
+unsafe fn populate(range: NonNull<[u8]>) {
+    if madvise_wrapper(range, libc::MADV_POPULATE_WRITE).is_err() {
+        // MADV_POPULATE_WRITE is only supported since Linux 5.14, touch every page manually instead.
+        let ptr = range.cast::<u8>().as_ptr();
+        for offset in (0..range.len()).step_by(1 << 12) {
+            let ptr = ptr.add(offset);
+            std::ptr::write_volatile(ptr, std::ptr::read_volatile(ptr));
+        }
+    }
+}
+
+fn mmap_wrapper(size: usize, extra_flags: libc::c_int) -> io::Result<NonNull<[u8]>> {
     assert!(size > 0);
     // Safety: Call format fits mmap manpage, should be safe
     unsafe {
@@ -74,46 +938,89 @@ fn mmap_wrapper(size: usize) -> io::Result<NonNull<[u8]>> {
             std::ptr::null_mut(),
             size,
             libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | extra_flags,
             -1,
             0,
         ) {
-            libc::MAP_FAILED => {
-                let errno = *libc::__errno_location();
-                let err = std::io::Error::from_raw_os_error(errno);
-                Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("mmap returned error: {}", err),
-                ))
-            }
+            libc::MAP_FAILED => Err(io::Error::last_os_error()),
             ptr => match NonNull::new(ptr as *mut u8) {
                 Some(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, size)),
-                None => Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "mmap returned null pointer",
-                )),
+                // mmap never returns null unless MAP_FIXED is used, treat it as if no memory was available.
+                None => Err(io::Error::from_raw_os_error(libc::ENOMEM)),
             },
         }
     }
 }
 
+unsafe fn madvise_wrapper(range: NonNull<[u8]>, advice: libc::c_int) -> io::Result<()> {
+    match libc::madvise(
+        range.cast::<u8>().as_ptr() as *mut libc::c_void,
+        range.len(),
+        advice,
+    ) {
+        0 => Ok(()),
+        -1 => Err(io::Error::last_os_error()),
+        x => Err(io::Error::other(format!(
+            "unexpected return value from madvise: {}. Expected 0 or -1",
+            x
+        ))),
+    }
+}
+
+unsafe fn mremap_wrapper(page: NonNull<[u8]>, new_size: usize) -> io::Result<NonNull<[u8]>> {
+    match libc::mremap(
+        page.cast::<u8>().as_ptr() as *mut libc::c_void,
+        page.len(),
+        new_size,
+        libc::MREMAP_MAYMOVE,
+    ) {
+        libc::MAP_FAILED => Err(io::Error::last_os_error()),
+        ptr => match NonNull::new(ptr as *mut u8) {
+            Some(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, new_size)),
+            None => Err(io::Error::from_raw_os_error(libc::ENOMEM)),
+        },
+    }
+}
+
+/// Returns the number of bytes in range that are resident in memory.
+unsafe fn mincore_wrapper(range: NonNull<[u8]>) -> io::Result<usize> {
+    let num_pages = range.len().div_ceil(1 << 12);
+    let mut vec = vec![0u8; num_pages];
+    match libc::mincore(
+        range.cast::<u8>().as_ptr() as *mut libc::c_void,
+        range.len(),
+        vec.as_mut_ptr(),
+    ) {
+        0 => Ok((vec.iter().filter(|&&x| x & 1 != 0).count() << 12).min(range.len())),
+        -1 => Err(io::Error::last_os_error()),
+        x => Err(io::Error::other(format!(
+            "unexpected return value from mincore: {}. Expected 0 or -1",
+            x
+        ))),
+    }
+}
+
+unsafe fn mlock_wrapper(range: NonNull<[u8]>) -> io::Result<()> {
+    match libc::mlock(
+        range.cast::<u8>().as_ptr() as *const libc::c_void,
+        range.len(),
+    ) {
+        0 => Ok(()),
+        -1 => Err(io::Error::last_os_error()),
+        x => Err(io::Error::other(format!(
+            "unexpected return value from mlock: {}. Expected 0 or -1",
+            x
+        ))),
+    }
+}
+
 unsafe fn munmap_wrapper(ptr: *mut u8, size: usize) -> io::Result<()> {
     match libc::munmap(ptr as *mut libc::c_void, size) {
         0 => Ok(()),
-        -1 => {
-            let errno = *libc::__errno_location();
-            let err = std::io::Error::from_raw_os_error(errno);
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("failed to free memory: {}", err),
-            ))
-        }
-        x => Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "unexpected return value from munmap: {}. Expected 0 or -1",
-                x
-            ),
-        )),
+        -1 => Err(io::Error::last_os_error()),
+        x => Err(io::Error::other(format!(
+            "unexpected return value from munmap: {}. Expected 0 or -1",
+            x
+        ))),
     }
 }
@@ -0,0 +1,321 @@
+use core::alloc::AllocError;
+use core::ptr::NonNull;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::sync::Mutex;
+
+use super::PageAlloc;
+use crate::util::align_down;
+
+// Use this to avoid creating aliased pointers.
+type Ptr = usize;
+
+#[derive(Clone, Copy)]
+struct Page {
+    ptr: Ptr,
+    len: usize,
+    offset: u64,
+}
+
+#[derive(Clone, Copy)]
+struct FileRange {
+    offset: u64,
+    len: usize,
+}
+
+/// Ranges of the file that are not used by any page. Adjacent ranges are merged.
+#[derive(Default)]
+struct FreeFileRanges {
+    // offset -> length
+    by_offset: BTreeMap<u64, usize>,
+    // (length, offset), to find the smallest range that fits
+    by_len: BTreeSet<(usize, u64)>,
+}
+
+impl FreeFileRanges {
+    fn remove(&mut self, range: FileRange) {
+        self.by_offset.remove(&range.offset);
+        self.by_len.remove(&(range.len, range.offset));
+    }
+
+    /// Adds the range, merging it with the adjacent free ranges.
+    fn insert(&mut self, mut range: FileRange) {
+        if let Some((&offset, &len)) = self.by_offset.range(..range.offset).next_back() {
+            if offset + len as u64 == range.offset {
+                self.remove(FileRange { offset, len });
+                range = FileRange {
+                    offset,
+                    len: len + range.len,
+                };
+            }
+        }
+        let end = range.offset + range.len as u64;
+        if let Some(&len) = self.by_offset.get(&end) {
+            self.remove(FileRange { offset: end, len });
+            range.len += len;
+        }
+        self.by_offset.insert(range.offset, range.len);
+        self.by_len.insert((range.len, range.offset));
+    }
+
+    /// Takes len bytes from the smallest free range that is big enough and returns their offset.
+    fn take(&mut self, len: usize) -> Option<u64> {
+        let &(range_len, offset) = self.by_len.range((len, 0)..).next()?;
+        self.remove(FileRange {
+            offset,
+            len: range_len,
+        });
+        if range_len > len {
+            self.insert(FileRange {
+                offset: offset + len as u64,
+                len: range_len - len,
+            });
+        }
+        Some(offset)
+    }
+}
+
+pub struct Config {
+    name: CString,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            name: CString::new("s_alloc").unwrap(),
+        }
+    }
+
+    /// Name of the memfd, it shows up in /proc/self/fd and /proc/self/maps.
+    pub fn name(&mut self, name: &CStr) -> &mut Self {
+        self.name = name.to_owned();
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Inner {
+    // start address -> page
+    pages: BTreeMap<Ptr, Page>,
+    free_ranges: FreeFileRanges,
+    file_len: u64,
+}
+
+/// Page allocator that places all pages in a single memfd file, so they can be mapped by other processes.
+///
+/// The file descriptor can be passed to another process, for example over a unix socket or by forking,
+/// and pages can be mapped there using [MemfdMapping::new] and the offsets from [MemfdPageAlloc::file_offset].
+pub struct MemfdPageAlloc {
+    fd: OwnedFd,
+    inner: Mutex<Inner>,
+}
+
+impl MemfdPageAlloc {
+    pub fn new(config: Config) -> io::Result<Self> {
+        // Safety: name is a valid null terminated string
+        let fd = unsafe { libc::memfd_create(config.name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: memfd_create returned a new file descriptor that we own
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        Ok(Self {
+            fd,
+            inner: Mutex::new(Inner {
+                pages: BTreeMap::new(),
+                free_ranges: FreeFileRanges::default(),
+                file_len: 0,
+            }),
+        })
+    }
+
+    /// Returns the offset in the file that the memory at ptr is stored at,
+    /// or None if ptr isn't inside a page allocated from this allocator.
+    pub fn file_offset(&self, ptr: NonNull<u8>) -> Option<u64> {
+        let ptr = ptr.as_ptr() as usize;
+        let inner = self.inner.lock().unwrap();
+        Self::find_page(&inner, ptr).map(|page| page.offset + (ptr - page.ptr) as u64)
+    }
+
+    fn find_page(inner: &Inner, ptr: Ptr) -> Option<Page> {
+        let (_, &page) = inner.pages.range(..=ptr).next_back()?;
+        (ptr < page.ptr + page.len).then_some(page)
+    }
+
+    fn take_file_range(&self, inner: &mut Inner, len: usize) -> io::Result<u64> {
+        if let Some(offset) = inner.free_ranges.take(len) {
+            return Ok(offset);
+        }
+
+        let offset = inner.file_len;
+        let new_len = offset + len as u64;
+        // Safety: fd is a valid file descriptor that we own
+        if unsafe { libc::ftruncate(self.fd.as_raw_fd(), new_len as libc::off_t) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        inner.file_len = new_len;
+
+        Ok(offset)
+    }
+
+    /// Frees the memory backing the given range of the file, it reads as zeroes afterwards.
+    fn punch_hole(&self, offset: u64, len: usize) {
+        // Safety: fd is a valid file descriptor that we own.
+        // Failing to punch a hole isn't an error since the file range is still usable.
+        unsafe {
+            libc::fallocate(
+                self.fd.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            );
+        }
+    }
+}
+
+impl AsFd for MemfdPageAlloc {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+// Safety: moving the struct doesn't invalidate currently allocated pages
+unsafe impl PageAlloc for MemfdPageAlloc {
+    fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(size > 0);
+
+        let len = size.next_multiple_of(1 << 12);
+
+        let mut inner = self.inner.lock().unwrap();
+        let offset = self
+            .take_file_range(&mut inner, len)
+            .map_err(|_| AllocError)?;
+
+        match mmap_shared(self.fd.as_fd(), offset, len, true) {
+            Ok(ptr) => {
+                let page_ptr = ptr.as_ptr() as usize;
+                inner.pages.insert(
+                    page_ptr,
+                    Page {
+                        ptr: page_ptr,
+                        len,
+                        offset,
+                    },
+                );
+                Ok(NonNull::slice_from_raw_parts(ptr, len))
+            }
+            Err(_) => {
+                inner.free_ranges.insert(FileRange { offset, len });
+                Err(AllocError)
+            }
+        }
+    }
+
+    unsafe fn dealloc_page(&self, page: NonNull<[u8]>) {
+        let ptr = page.cast::<u8>().as_ptr() as usize;
+
+        let mut inner = self.inner.lock().unwrap();
+        let page = inner.pages.remove(&ptr).expect("find memfd page");
+
+        // Leak the file range if unmapping fails, so it isn't reused while it is still mapped.
+        if libc::munmap(page.ptr as *mut libc::c_void, page.len) != 0 {
+            return;
+        }
+
+        self.punch_hole(page.offset, page.len);
+        inner.free_ranges.insert(FileRange {
+            offset: page.offset,
+            len: page.len,
+        });
+    }
+
+    fn decommits(&self) -> bool {
+        true
+    }
+
+    unsafe fn decommit(&self, range: NonNull<[u8]>) {
+        let ptr = range.cast::<u8>().as_ptr() as usize;
+        let page = Self::find_page(&self.inner.lock().unwrap(), ptr).expect("find memfd page");
+        // Memory of a shared mapping is only freed when it is removed from the file
+        self.punch_hole(page.offset + (ptr - page.ptr) as u64, range.len());
+    }
+}
+
+/// Mapping of a range of a memfd file that was created by [MemfdPageAlloc], possibly in another process.
+///
+/// The range is unmapped on drop.
+pub struct MemfdMapping {
+    map_ptr: NonNull<u8>,
+    map_len: usize,
+    data: NonNull<[u8]>,
+}
+
+impl MemfdMapping {
+    /// Maps len bytes starting at offset from the file, offset doesn't have to be page aligned.
+    pub fn new(fd: BorrowedFd<'_>, offset: u64, len: usize, writable: bool) -> io::Result<Self> {
+        assert!(len > 0);
+
+        let map_offset = align_down(offset as usize, 1 << 12) as u64;
+        let head = (offset - map_offset) as usize;
+        let map_len = (head + len).next_multiple_of(1 << 12);
+        let map_ptr = mmap_shared(fd, map_offset, map_len, writable)?;
+
+        // Safety: head is smaller than map_len
+        let data_ptr = unsafe { map_ptr.add(head) };
+        Ok(Self {
+            map_ptr,
+            map_len,
+            data: NonNull::slice_from_raw_parts(data_ptr, len),
+        })
+    }
+
+    /// Returns a pointer to the mapped range.
+    ///
+    /// Contents of the memory can be changed by other processes at any time so it is
+    /// up to the user to synchronize access.
+    pub fn as_ptr(&self) -> NonNull<[u8]> {
+        self.data
+    }
+}
+
+impl Drop for MemfdMapping {
+    fn drop(&mut self) {
+        // Safety: we own this mapping and it can't be referenced after drop.
+        unsafe { libc::munmap(self.map_ptr.as_ptr() as *mut libc::c_void, self.map_len) };
+    }
+}
+
+fn mmap_shared(
+    fd: BorrowedFd<'_>,
+    offset: u64,
+    len: usize,
+    writable: bool,
+) -> io::Result<NonNull<u8>> {
+    let prot = if writable {
+        libc::PROT_READ | libc::PROT_WRITE
+    } else {
+        libc::PROT_READ
+    };
+    // Safety: Call format fits mmap manpage, should be safe
+    unsafe {
+        match libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            prot,
+            libc::MAP_SHARED,
+            fd.as_raw_fd(),
+            offset as libc::off_t,
+        ) {
+            libc::MAP_FAILED => Err(io::Error::last_os_error()),
+            ptr => NonNull::new(ptr as *mut u8).ok_or(io::Error::from_raw_os_error(libc::ENOMEM)),
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use core::alloc::AllocError;
+use core::ptr::NonNull;
+use std::io;
+use std::sync::Mutex;
+
+use super::{realloc_page_by_copy, PageAlloc};
+use crate::compact_ptr::CompactPtr;
+use crate::util::align_up;
+
+// Use this to avoid creating aliased pointers.
+type Ptr = usize;
+
+const CHUNK_SIZE: usize = 1 << 21; // 2 MB
+
+#[derive(Clone, Copy)]
+struct Slice {
+    ptr: Ptr,
+    len: usize,
+}
+
+impl Slice {
+    fn from_page(page: NonNull<[u8]>) -> Self {
+        Self {
+            ptr: page.cast::<u8>().as_ptr() as usize,
+            len: page.len(),
+        }
+    }
+
+    fn to_page(self) -> NonNull<[u8]> {
+        let ptr = NonNull::new(self.ptr as *mut u8).unwrap();
+        NonNull::slice_from_raw_parts(ptr, self.len)
+    }
+}
+
+pub struct Config {
+    size: usize,
+    base_addr: Option<usize>,
+}
+
+impl Config {
+    /// size is the size of the reserved address range, it is rounded up to a multiple of 2MB.
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            base_addr: None,
+        }
+    }
+
+    /// Address to reserve the range at, it has to be a multiple of 2MB.
+    /// By default the kernel chooses the address.
+    ///
+    /// This can be used to place all pages below 4GB, so pointers to them fit into 32 bits.
+    pub fn base_addr(&mut self, base_addr: usize) -> &mut Self {
+        self.base_addr = Some(base_addr);
+        self
+    }
+}
+
+/// Page allocator that reserves a contiguous range of virtual addresses up front
+/// and allocates all pages inside it.
+///
+/// The range is divided into 2MB chunks, a bitmap keeps track of which chunks are in use.
+/// Free chunks are inaccessible, so they don't count towards committed memory.
+/// Decommitted ranges inside pages stay accessible, so recommitting them can't fail.
+pub struct RegionPageAlloc {
+    base: Ptr,
+    size: usize,
+    // bit i is set if chunk i is in use
+    bitmap: Mutex<Vec<u64>>,
+}
+
+impl RegionPageAlloc {
+    pub fn new(config: Config) -> io::Result<Self> {
+        let size = config.size.next_multiple_of(CHUNK_SIZE);
+        assert!(size > 0);
+
+        let base = match config.base_addr {
+            Some(base_addr) => reserve_fixed(base_addr, size)?,
+            None => reserve(size)?,
+        };
+        let num_chunks = size / CHUNK_SIZE;
+
+        Ok(Self {
+            base,
+            size,
+            bitmap: Mutex::new(vec![0; num_chunks.div_ceil(64)]),
+        })
+    }
+
+    /// Start address of the reserved range.
+    pub fn base_addr(&self) -> usize {
+        self.base
+    }
+
+    /// Size of the reserved range.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns true if ptr points inside the reserved range.
+    pub fn contains(&self, ptr: NonNull<u8>) -> bool {
+        let ptr = ptr.as_ptr() as usize;
+        ptr >= self.base && ptr < self.base + self.size
+    }
+
+    /// Converts ptr to an offset from the start of the range.
+    /// Returns None if ptr isn't inside the range or if the offset doesn't fit into 32 bits.
+    pub fn compact<T>(&self, ptr: NonNull<T>) -> Option<CompactPtr<T>> {
+        if !self.contains(ptr.cast()) {
+            return None;
+        }
+        CompactPtr::new(self.base, ptr)
+    }
+
+    /// Converts a pointer created by [RegionPageAlloc::compact] back to a full pointer.
+    pub fn expand<T>(&self, ptr: CompactPtr<T>) -> NonNull<T> {
+        ptr.to_ptr(self.base)
+    }
+
+    fn num_chunks(&self) -> usize {
+        self.size / CHUNK_SIZE
+    }
+
+    fn chunk_addr(&self, chunk: usize) -> Ptr {
+        self.base + chunk * CHUNK_SIZE
+    }
+
+    /// Finds num free chunks in a row, the first of which has an address aligned to align.
+    fn find_free_chunks(&self, bitmap: &[u64], num: usize, align: usize) -> Option<usize> {
+        let first = (align_up(self.base, align) - self.base) / CHUNK_SIZE;
+        let step = (align / CHUNK_SIZE).max(1);
+
+        let mut start = first;
+        while start + num <= self.num_chunks() {
+            let used = find_bit(bitmap, start, start + num, true);
+            if used == start + num {
+                return Some(start);
+            }
+            // the next candidate has to start after the used chunks
+            let free = find_bit(bitmap, used, self.num_chunks(), false);
+            start = first + align_up(free - first, step);
+        }
+
+        None
+    }
+}
+
+impl Drop for RegionPageAlloc {
+    fn drop(&mut self) {
+        // Safety: we own the reserved range and it can't be referenced after drop.
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.size) };
+    }
+}
+
+// Safety: moving the struct doesn't invalidate currently allocated pages
+unsafe impl PageAlloc for RegionPageAlloc {
+    fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc_page_aligned(size, 1 << 12)
+    }
+
+    fn zeroes_pages(&self) -> bool {
+        true
+    }
+
+    fn alloc_page_aligned(&self, size: usize, align: usize) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(size > 0);
+        assert!(align.is_power_of_two());
+
+        let num = size.div_ceil(CHUNK_SIZE);
+        let align = align.max(CHUNK_SIZE);
+
+        let mut bitmap = self.bitmap.lock().unwrap();
+        let start = self
+            .find_free_chunks(&bitmap, num, align)
+            .ok_or(AllocError)?;
+
+        let page = Slice {
+            ptr: self.chunk_addr(start),
+            len: num * CHUNK_SIZE,
+        };
+        // Safety: page is a free part of our reserved range, so nothing references it.
+        unsafe { commit(page) }.map_err(|_| AllocError)?;
+
+        for chunk in start..start + num {
+            set(&mut bitmap, chunk, true);
+        }
+
+        Ok(page.to_page())
+    }
+
+    unsafe fn dealloc_page(&self, page: NonNull<[u8]>) {
+        let page = Slice::from_page(page);
+
+        // The chunks are leaked if this fails, since they wouldn't be zeroed when they are reused.
+        if release(page).is_err() {
+            return;
+        }
+
+        let mut bitmap = self.bitmap.lock().unwrap();
+        let start = (page.ptr - self.base) / CHUNK_SIZE;
+        for chunk in start..start + page.len / CHUNK_SIZE {
+            set(&mut bitmap, chunk, false);
+        }
+    }
+
+    unsafe fn grow_page(
+        &self,
+        page: NonNull<[u8]>,
+        new_size: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        assert!(new_size > page.len());
+
+        let page = Slice::from_page(page);
+        let new_len = new_size.next_multiple_of(CHUNK_SIZE);
+
+        {
+            let mut bitmap = self.bitmap.lock().unwrap();
+            let start = (page.ptr - self.base) / CHUNK_SIZE;
+            let old_end = start + page.len / CHUNK_SIZE;
+            let new_end = start + new_len / CHUNK_SIZE;
+
+            // Try to grow into the chunks that come after the page
+            if new_end <= self.num_chunks() && find_bit(&bitmap, old_end, new_end, true) == new_end
+            {
+                let grown = Slice {
+                    ptr: page.ptr + page.len,
+                    len: new_len - page.len,
+                };
+                if commit(grown).is_ok() {
+                    for chunk in old_end..new_end {
+                        set(&mut bitmap, chunk, true);
+                    }
+                    return Ok(Slice {
+                        ptr: page.ptr,
+                        len: new_len,
+                    }
+                    .to_page());
+                }
+            }
+        }
+
+        realloc_page_by_copy(self, page.to_page(), new_size)
+    }
+
+    fn decommits(&self) -> bool {
+        true
+    }
+
+    unsafe fn decommit(&self, range: NonNull<[u8]>) {
+        // The range is left accessible, changing its protection would split the mapping and
+        // recommitting it could then fail.
+        // This is best-effort so the result is ignored
+        libc::madvise(
+            range.cast::<u8>().as_ptr() as *mut libc::c_void,
+            range.len(),
+            libc::MADV_DONTNEED,
+        );
+    }
+}
+
+/// Returns the index of the first bit in [start, end) that is equal to value, or end if there isn't one.
+fn find_bit(bitmap: &[u64], start: usize, end: usize, value: bool) -> usize {
+    let mut idx = start;
+    while idx < end {
+        let word = if value {
+            bitmap[idx / 64]
+        } else {
+            !bitmap[idx / 64]
+        };
+        let rest = word >> (idx % 64);
+        if rest != 0 {
+            return (idx + rest.trailing_zeros() as usize).min(end);
+        }
+        idx = align_up(idx + 1, 64);
+    }
+    end
+}
+
+fn set(bitmap: &mut [u64], idx: usize, value: bool) {
+    if value {
+        bitmap[idx / 64] |= 1 << (idx % 64);
+    } else {
+        bitmap[idx / 64] &= !(1 << (idx % 64));
+    }
+}
+
+/// Reserves size bytes of address space aligned to 2MB, without committing any memory.
+fn reserve(size: usize) -> io::Result<Ptr> {
+    let reserved_len = size + CHUNK_SIZE;
+    // Safety: Call format fits mmap manpage, should be safe
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            reserved_len,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    // trim the range so it is aligned to 2MB, so chunks can be backed by transparent huge pages
+    let reserved_start = ptr as usize;
+    let start = align_up(reserved_start, CHUNK_SIZE);
+    let end = start + size;
+    // Safety: head and tail are parts of the range we just reserved.
+    // Unmapping them can only fail if the arguments are wrong, they would stay reserved otherwise.
+    unsafe {
+        if start > reserved_start {
+            let res = libc::munmap(ptr, start - reserved_start);
+            debug_assert_eq!(res, 0, "{}", io::Error::last_os_error());
+        }
+        if reserved_start + reserved_len > end {
+            let res = libc::munmap(
+                end as *mut libc::c_void,
+                reserved_start + reserved_len - end,
+            );
+            debug_assert_eq!(res, 0, "{}", io::Error::last_os_error());
+        }
+    }
+
+    Ok(start)
+}
+
+/// Reserves size bytes of address space starting at addr, without committing any memory.
+fn reserve_fixed(addr: usize, size: usize) -> io::Result<Ptr> {
+    if !addr.is_multiple_of(CHUNK_SIZE) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "base address has to be a multiple of 2MB",
+        ));
+    }
+
+    // Safety: Call format fits mmap manpage. MAP_FIXED_NOREPLACE never replaces existing mappings.
+    let ptr = unsafe {
+        libc::mmap(
+            addr as *mut libc::c_void,
+            size,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE
+                | libc::MAP_ANONYMOUS
+                | libc::MAP_NORESERVE
+                | libc::MAP_FIXED_NOREPLACE,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Kernels older than 4.17 treat MAP_FIXED_NOREPLACE as a hint
+    if ptr as usize != addr {
+        // Safety: we just created this mapping
+        unsafe { libc::munmap(ptr, size) };
+        return Err(io::Error::from_raw_os_error(libc::EEXIST));
+    }
+
+    Ok(addr)
+}
+
+/// Makes the range accessible.
+unsafe fn commit(range: Slice) -> io::Result<()> {
+    let ptr = range.ptr as *mut libc::c_void;
+    if libc::mprotect(ptr, range.len, libc::PROT_READ | libc::PROT_WRITE) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // This is best-effort so the result is ignored
+    libc::madvise(ptr, range.len, libc::MADV_HUGEPAGE);
+    Ok(())
+}
+
+/// Frees the memory backing the range and makes it inaccessible.
+unsafe fn release(range: Slice) -> io::Result<()> {
+    let ptr = range.ptr as *mut libc::c_void;
+    if libc::madvise(ptr, range.len, libc::MADV_DONTNEED) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if libc::mprotect(ptr, range.len, libc::PROT_NONE) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
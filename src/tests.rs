@@ -4,18 +4,52 @@ use std::ptr::NonNull;
 
 use crate::{
     bump_alloc::{self, BumpAlloc},
+    compact_ptr::CompactPtr,
     local_alloc::{self, LocalAlloc},
+    page_alloc::{
+        allocator_page_alloc::AllocatorPageAlloc,
+        budget_page_alloc::{self, BudgetPageAlloc},
+        caching_page_alloc::{self, CachingPageAlloc},
+        fallback_page_alloc::{self, FallbackPageAlloc},
+        fixed_buffer_page_alloc::FixedBufferPageAlloc,
+        DynamicPageAlloc, PageAlloc,
+    },
+};
+
+#[cfg(target_os = "linux")]
+use crate::page_alloc::file_page_alloc::{self, FilePageAlloc};
+
+#[cfg(target_os = "linux")]
+use crate::page_alloc::guarded_page_alloc::{self, GuardedPageAlloc};
+
+#[cfg(target_os = "linux")]
+use crate::page_alloc::memfd_page_alloc::{self, MemfdMapping, MemfdPageAlloc};
+
+#[cfg(target_os = "linux")]
+use crate::page_alloc::region_page_alloc::{self, RegionPageAlloc};
+
+#[cfg(target_os = "linux")]
+use crate::page_alloc::dynamic_page_alloc::{
+    self, CommitStrategy, DecommitStrategy, HugePagePolicy, HugeTlbSize, NumaPolicy, PageAttributes,
 };
 
 #[test]
 fn test_global_alloc() {
-    test_allocator_all(&std::alloc::Global);
+    test_allocator_all(std::alloc::Global);
 }
 
 #[test]
 fn test_local_alloc() {
     let alloc = LocalAlloc::new(local_alloc::Config::new(&std::alloc::Global));
-    test_allocator_all(alloc);
+    test_allocator_all(&alloc);
+    test_allocator_large_alignment(&alloc);
+
+    let page = std::alloc::Global
+        .alloc_page_aligned(1 << 16, 1 << 21)
+        .unwrap();
+    assert_eq!(page.cast::<u8>().align_offset(1 << 21), 0);
+    assert!(page.len() >= 1 << 16);
+    unsafe { std::alloc::Global.dealloc_page(page) };
 }
 
 #[test]
@@ -24,6 +58,12 @@ fn test_bump_alloc() {
     test_allocator_all(alloc);
 }
 
+#[test]
+fn test_bump_alloc_large_alignment() {
+    let alloc = BumpAlloc::new(bump_alloc::Config::new(std::alloc::Global));
+    test_allocator_large_alignment(alloc);
+}
+
 #[test]
 fn test_local_bump_alloc() {
     let alloc = LocalAlloc::new(local_alloc::Config::new(&std::alloc::Global));
@@ -31,6 +71,1261 @@ fn test_local_bump_alloc() {
     test_allocator_all(alloc);
 }
 
+#[cfg(target_os = "linux")]
+#[test]
+fn test_local_dynamic_page_alloc() {
+    for huge_pages in [
+        HugePagePolicy::None,
+        HugePagePolicy::TransparentAdvise,
+        HugePagePolicy::HugeTlbOrFallback(HugeTlbSize::Size2MB),
+    ] {
+        let mut config = dynamic_page_alloc::Config::new();
+        config.huge_pages(huge_pages);
+        let page_alloc = DynamicPageAlloc::new(config);
+        test_local_alloc_on(&page_alloc, 1 << 21);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_huge_page_advice() {
+    // the advice is ignored if the kernel doesn't support transparent huge pages
+    if !std::path::Path::new("/sys/kernel/mm/transparent_hugepage").exists() {
+        return;
+    }
+
+    for (huge_pages, flag) in [
+        (HugePagePolicy::None, "nh"),
+        (HugePagePolicy::TransparentAdvise, "hg"),
+    ] {
+        let mut config = dynamic_page_alloc::Config::new();
+        config.huge_pages(huge_pages);
+        let page_alloc = DynamicPageAlloc::new(config);
+        let page = page_alloc.alloc_page(1 << 21).unwrap();
+        assert!(mapping_vm_flags(page.cast::<u8>().as_ptr()).contains(&flag.to_owned()));
+        unsafe { page_alloc.dealloc_page(page) };
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_local_dynamic_page_alloc_large_alignment() {
+    let page_alloc = DynamicPageAlloc::default();
+    let mut config = local_alloc::Config::new(&page_alloc);
+    config.min_page_size(1 << 21);
+    let alloc = LocalAlloc::new(config);
+    test_allocator_large_alignment(&alloc);
+
+    let page = page_alloc.alloc_page_aligned(1 << 21, 1 << 30).unwrap();
+    assert_eq!(page.cast::<u8>().align_offset(1 << 30), 0);
+    unsafe { page_alloc.dealloc_page(page) };
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_local_dynamic_page_alloc_commit() {
+    for commit in [
+        CommitStrategy::Eager,
+        CommitStrategy::Lazy,
+        CommitStrategy::PopulatePrefix(1 << 16),
+        CommitStrategy::PopulatePrefix(1 << 30),
+    ] {
+        let mut config = dynamic_page_alloc::Config::new();
+        config.commit(commit);
+        let page_alloc = DynamicPageAlloc::new(config);
+        test_local_alloc_on(&page_alloc, 1 << 21);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_local_dynamic_page_alloc_decommit() {
+    for decommit in [DecommitStrategy::DontNeed, DecommitStrategy::Free] {
+        let mut config = dynamic_page_alloc::Config::new();
+        config.decommit(decommit);
+        let page_alloc = DynamicPageAlloc::new(config);
+        let mut config = local_alloc::Config::new(&page_alloc);
+        config.min_page_size(1 << 21).decommit_threshold(1 << 12);
+        let alloc = LocalAlloc::new(config);
+        test_allocator_all(&alloc);
+
+        let mut v = Vec::<u8, _>::new_in(&alloc);
+        for i in 0..1 << 20 {
+            v.push(i as u8);
+        }
+        assert!(v.iter().enumerate().all(|(i, &x)| x == i as u8));
+    }
+}
+
+/// Page allocator that counts the bytes that are decommitted and recommitted through it.
+struct DecommitCounter<P: PageAlloc> {
+    page_alloc: P,
+    decommitted: std::cell::Cell<usize>,
+    recommitted: std::cell::Cell<usize>,
+}
+
+unsafe impl<P: PageAlloc> PageAlloc for DecommitCounter<P> {
+    fn alloc_page(&self, size: usize) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+        self.page_alloc.alloc_page(size)
+    }
+
+    unsafe fn dealloc_page(&self, page: NonNull<[u8]>) {
+        self.page_alloc.dealloc_page(page)
+    }
+
+    fn decommits(&self) -> bool {
+        self.page_alloc.decommits()
+    }
+
+    unsafe fn decommit(&self, range: NonNull<[u8]>) {
+        self.decommitted.set(self.decommitted.get() + range.len());
+        self.page_alloc.decommit(range)
+    }
+
+    unsafe fn recommit(&self, range: NonNull<[u8]>) {
+        self.recommitted.set(self.recommitted.get() + range.len());
+        self.page_alloc.recommit(range)
+    }
+}
+
+#[test]
+fn test_local_alloc_decommit_freed_part() {
+    let page_alloc = DecommitCounter {
+        page_alloc: std::alloc::Global,
+        decommitted: std::cell::Cell::new(0),
+        recommitted: std::cell::Cell::new(0),
+    };
+    let mut config = local_alloc::Config::new(&page_alloc);
+    config.min_page_size(1 << 20).decommit_threshold(1 << 16);
+    let alloc = LocalAlloc::new(config);
+
+    let layout = Layout::from_size_align(1 << 18, 1 << 12).unwrap();
+    let small_layout = Layout::from_size_align(100, 8).unwrap();
+    let big = alloc.allocate(layout).unwrap();
+    let small = alloc.allocate(small_layout).unwrap();
+    let _last = alloc.allocate(layout).unwrap();
+
+    unsafe { alloc.deallocate(big.cast::<u8>(), layout) };
+    assert_eq!(page_alloc.decommitted.get(), 1 << 18);
+
+    // only the 4KB page that contains the small allocation is decommitted, not the merged range
+    unsafe { alloc.deallocate(small.cast::<u8>(), small_layout) };
+    assert_eq!(page_alloc.decommitted.get(), (1 << 18) + (1 << 12));
+
+    // only the parts of the merged decommitted range that are used again are recommitted
+    let big = alloc.allocate(layout).unwrap();
+    assert_eq!(page_alloc.recommitted.get(), 1 << 18);
+    let small = alloc.allocate(small_layout).unwrap();
+    assert_eq!(page_alloc.recommitted.get(), (1 << 18) + (1 << 12));
+    unsafe {
+        alloc.deallocate(small.cast::<u8>(), small_layout);
+        alloc.deallocate(big.cast::<u8>(), layout);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_local_alloc_decommit_new_page_tail() {
+    let mut config = dynamic_page_alloc::Config::new();
+    config
+        .huge_pages(HugePagePolicy::None)
+        .commit(CommitStrategy::Eager);
+    let page_alloc = DynamicPageAlloc::new(config);
+    let mut config = local_alloc::Config::new(&page_alloc);
+    config.min_page_size(1 << 22).decommit_threshold(1 << 16);
+    let alloc = LocalAlloc::new(config);
+
+    let layout = Layout::from_size_align(1 << 12, 1 << 12).unwrap();
+    let small_layout = Layout::from_size_align(100, 8).unwrap();
+    let first = alloc.allocate(layout).unwrap();
+    let small = alloc.allocate(small_layout).unwrap();
+    assert_eq!(alloc.residency().unwrap().resident, 1 << 22);
+
+    // the tail of the page was never decommitted, so it is decommitted with the small allocation
+    unsafe { alloc.deallocate(small.cast::<u8>(), small_layout) };
+    assert_eq!(alloc.residency().unwrap().resident, 1 << 12);
+    unsafe { alloc.deallocate(first.cast::<u8>(), layout) };
+}
+
+#[test]
+fn test_global_page_resize() {
+    test_page_resize(&std::alloc::Global);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_local_dynamic_page_alloc_grow() {
+    let page_alloc = DynamicPageAlloc::default();
+    test_page_resize(&page_alloc);
+
+    let mut config = local_alloc::Config::new(&page_alloc);
+    config.min_page_size(1 << 21);
+    let alloc = LocalAlloc::new(config);
+    let mut v = Vec::<u32, _>::new_in(&alloc);
+    for i in 0..1 << 22 {
+        v.push(i);
+    }
+    assert!(v.iter().enumerate().all(|(i, &x)| x == i as u32));
+    drop(v);
+
+    // pages that fell back to normal pages are resized in multiples of 2MB
+    let mut config = dynamic_page_alloc::Config::new();
+    config.huge_pages(HugePagePolicy::HugeTlbOrFallback(HugeTlbSize::Size1GB));
+    let page_alloc = DynamicPageAlloc::new(config);
+    let mut page = page_alloc.alloc_page(1 << 21).unwrap();
+    if page.len() < 1 << 30 {
+        page = unsafe { page_alloc.grow_page(page, 3 << 20) }.unwrap();
+        assert_eq!(page.len(), 1 << 22);
+        page = unsafe { page_alloc.shrink_page(page, 1 << 20) }.unwrap();
+        assert_eq!(page.len(), 1 << 21);
+    }
+    unsafe { page_alloc.dealloc_page(page) };
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_local_dynamic_page_alloc_numa() {
+    for numa in [
+        NumaPolicy::Default,
+        NumaPolicy::Bind(0),
+        NumaPolicy::Interleave(1),
+        NumaPolicy::Local,
+    ] {
+        let mut config = dynamic_page_alloc::Config::new();
+        config.numa(numa);
+        let page_alloc = DynamicPageAlloc::new(config);
+
+        let page = page_alloc.alloc_page(1 << 21).unwrap();
+        let node = page_alloc.page_node(page).unwrap();
+        if let NumaPolicy::Bind(0) | NumaPolicy::Interleave(1) = numa {
+            assert_eq!(node, 0);
+        }
+        unsafe { page_alloc.dealloc_page(page) };
+
+        test_local_alloc_on(&page_alloc, 1 << 21);
+    }
+
+    // nodes that don't exist are an error
+    let mut config = dynamic_page_alloc::Config::new();
+    config.numa(NumaPolicy::Bind(63));
+    let page_alloc = DynamicPageAlloc::new(config);
+    assert!(page_alloc.alloc_page(1 << 21).is_err());
+    assert_eq!(
+        page_alloc.last_error().unwrap().raw_os_error(),
+        Some(libc::EINVAL)
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_guarded_page_alloc() {
+    let page_alloc =
+        GuardedPageAlloc::new(guarded_page_alloc::Config::new(DynamicPageAlloc::default()));
+
+    let alloc = test_local_alloc_on(&page_alloc, 1 << 16);
+    test_allocator_large_alignment(&alloc);
+    let alloc = BumpAlloc::new(bump_alloc::Config::new(&alloc));
+    test_allocator_all(alloc);
+
+    test_page_resize(&page_alloc);
+
+    let page = page_alloc.alloc_page(1 << 16).unwrap();
+    let ptr = page.cast::<u8>().as_ptr();
+    unsafe {
+        ptr.write(1);
+        ptr.add(page.len() - 1).write(1);
+    }
+    assert!(crashes_in_child(|| unsafe { ptr.add(page.len()).write(1) }));
+    assert!(crashes_in_child(|| unsafe { ptr.sub(1).write(1) }));
+    unsafe { page_alloc.dealloc_page(page) };
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_memfd_page_alloc() {
+    use std::os::fd::AsFd;
+
+    let page_alloc = MemfdPageAlloc::new(memfd_page_alloc::Config::new()).unwrap();
+
+    let mut config = local_alloc::Config::new(&page_alloc);
+    config.min_page_size(1 << 16).decommit_threshold(1 << 12);
+    let alloc = LocalAlloc::new(config);
+    test_allocator_all(&alloc);
+    test_page_resize(&page_alloc);
+
+    let mut v = Vec::<u32, _>::with_capacity_in(1 << 10, &alloc);
+    v.extend(0..1 << 10);
+    let offset = page_alloc
+        .file_offset(NonNull::new(v.as_mut_ptr().cast::<u8>()).unwrap())
+        .unwrap();
+
+    // map the same memory a second time, like another process would
+    let mapping = MemfdMapping::new(page_alloc.as_fd(), offset, v.len() * 4, true).unwrap();
+    let shared = mapping.as_ptr().cast::<u32>().as_ptr();
+    unsafe {
+        for (i, x) in v.iter().enumerate() {
+            assert_eq!(*shared.add(i), *x);
+        }
+        shared.write_volatile(69);
+    }
+    assert_eq!(v[0], 69);
+
+    // freed file ranges are merged and reused
+    let page_alloc = MemfdPageAlloc::new(memfd_page_alloc::Config::new()).unwrap();
+    let pages = [0; 3].map(|_| page_alloc.alloc_page(1 << 12).unwrap());
+    let first_offset = page_alloc.file_offset(pages[0].cast::<u8>()).unwrap();
+    unsafe {
+        page_alloc.dealloc_page(pages[1]);
+        page_alloc.dealloc_page(pages[0]);
+    }
+    let page = page_alloc.alloc_page(1 << 13).unwrap();
+    assert_eq!(
+        page_alloc.file_offset(page.cast::<u8>()),
+        Some(first_offset)
+    );
+    unsafe {
+        page_alloc.dealloc_page(page);
+        page_alloc.dealloc_page(pages[2]);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_file_page_alloc() {
+    let path = std::env::temp_dir().join(format!("s_alloc_test_{}", std::process::id()));
+
+    let page_alloc = FilePageAlloc::create(file_page_alloc::Config::new(&path, 1 << 26)).unwrap();
+    test_local_alloc_on(&page_alloc, 1 << 20);
+    {
+        let mut config = local_alloc::Config::new(&page_alloc);
+        config.min_page_size(1 << 20).free_after(usize::MAX);
+        let alloc = LocalAlloc::new(config);
+        let alloc = BumpAlloc::new(bump_alloc::Config::new(&alloc));
+
+        let mut v = Vec::<u64, _>::new_in(&alloc);
+        v.extend(0..1000);
+        // the data is read again after reopening the file, so it is never freed
+        let root = v.leak();
+        page_alloc.set_root(NonNull::new(root.as_mut_ptr().cast::<u8>()));
+    }
+    page_alloc.sync().unwrap();
+    let base_addr = page_alloc.base_addr();
+
+    // address range is still in use
+    assert!(FilePageAlloc::open(&path).is_err());
+    // the existing file is kept if the new one can't be mapped
+    let mut config = file_page_alloc::Config::new(&path, 1 << 26);
+    config.base_addr(base_addr);
+    assert!(FilePageAlloc::create(config).is_err());
+    drop(page_alloc);
+
+    let page_alloc = FilePageAlloc::open(&path).unwrap();
+    assert_eq!(page_alloc.base_addr(), base_addr);
+    let root = page_alloc.root().unwrap().cast::<u64>().as_ptr();
+    for i in 0..1000 {
+        assert_eq!(unsafe { *root.add(i) }, i as u64);
+    }
+
+    // new pages don't overwrite the old ones
+    let page = page_alloc.alloc_page(1 << 12).unwrap();
+    unsafe { page.cast::<u8>().as_ptr().write_bytes(0xff, page.len()) };
+    for i in 0..1000 {
+        assert_eq!(unsafe { *root.add(i) }, i as u64);
+    }
+
+    drop(page_alloc);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_region_page_alloc() {
+    let page_alloc = RegionPageAlloc::new(region_page_alloc::Config::new(1 << 30)).unwrap();
+    assert_eq!(page_alloc.base_addr() % (1 << 21), 0);
+
+    {
+        let mut config = local_alloc::Config::new(&page_alloc);
+        config.min_page_size(1 << 21).decommit_threshold(1 << 12);
+        let alloc = LocalAlloc::new(config);
+        test_allocator_all(&alloc);
+        test_allocator_large_alignment(&alloc);
+
+        let mut v = Vec::<u32, _>::new_in(&alloc);
+        for i in 0..1 << 22 {
+            v.push(i);
+        }
+        assert!(v.iter().enumerate().all(|(i, &x)| x == i as u32));
+        assert!(page_alloc.contains(NonNull::new(v.as_mut_ptr().cast::<u8>()).unwrap()));
+    }
+
+    test_page_resize(&page_alloc);
+
+    // decommitted ranges stay accessible and read as zero, so recommitting them can't fail
+    let page = page_alloc.alloc_page(1 << 21).unwrap();
+    let ptr = page.cast::<u8>().as_ptr();
+    unsafe {
+        ptr.write_bytes(1, page.len());
+        page_alloc.decommit(NonNull::slice_from_raw_parts(page.cast::<u8>(), 1 << 20));
+    }
+    assert!(!crashes_in_child(|| unsafe { ptr.write(1) }));
+    unsafe {
+        assert_eq!(ptr.read(), 0);
+        assert_eq!(ptr.add(1 << 20).read(), 1);
+        page_alloc.recommit(NonNull::slice_from_raw_parts(page.cast::<u8>(), 1 << 20));
+        page_alloc.dealloc_page(page);
+    }
+
+    let pages = (0..512)
+        .map(|_| page_alloc.alloc_page(1 << 21).unwrap())
+        .collect::<Vec<_>>();
+    assert!(page_alloc.alloc_page(1).is_err());
+
+    // free chunks are found across words of the bitmap
+    let freed = |i: usize| (60..70).contains(&i) && i != 63;
+    for i in (0..512).filter(|&i| freed(i)) {
+        unsafe { page_alloc.dealloc_page(pages[i]) };
+    }
+    let page = page_alloc.alloc_page(6 << 21).unwrap();
+    assert_eq!(page.cast::<u8>(), pages[64].cast::<u8>());
+    assert!(page_alloc.alloc_page(4 << 21).is_err());
+    let small_page = page_alloc.alloc_page(3 << 21).unwrap();
+    assert_eq!(small_page.cast::<u8>(), pages[60].cast::<u8>());
+    unsafe {
+        page_alloc.dealloc_page(page);
+        page_alloc.dealloc_page(small_page);
+    }
+
+    let ptr = pages[0].cast::<u8>().as_ptr();
+    for (i, page) in pages.into_iter().enumerate() {
+        if !freed(i) {
+            unsafe { page_alloc.dealloc_page(page) };
+        }
+    }
+    assert!(crashes_in_child(|| unsafe { ptr.write(1) }));
+    assert!(!page_alloc.contains(NonNull::new(ptr.wrapping_sub(1)).unwrap()));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_low_address_region_page_alloc() {
+    let base_addr = 1 << 30;
+    let mut config = region_page_alloc::Config::new(1 << 28);
+    config.base_addr(base_addr);
+    let page_alloc = RegionPageAlloc::new(config).unwrap();
+    assert_eq!(page_alloc.base_addr(), base_addr);
+
+    let mut config = region_page_alloc::Config::new(1 << 21);
+    config.base_addr(base_addr);
+    assert_eq!(
+        RegionPageAlloc::new(config).err().unwrap().raw_os_error(),
+        Some(libc::EEXIST)
+    );
+
+    let mut config = local_alloc::Config::new(&page_alloc);
+    config.min_page_size(1 << 21);
+    let alloc = LocalAlloc::new(config);
+    let mut ptrs = Vec::new();
+    for i in 0..1000u64 {
+        let ptr = Box::leak(Box::new_in(i, &alloc));
+        let ptr = NonNull::from(ptr);
+        // pointers below 4GB can be compacted without a base
+        let low = CompactPtr::new(0, ptr).unwrap();
+        assert_eq!(low.to_ptr(0), ptr);
+        let compact = page_alloc.compact(ptr).unwrap();
+        assert_eq!(page_alloc.expand(compact), ptr);
+        ptrs.push(compact);
+    }
+    // compact pointers can be sent to other threads like the pointers they replace
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for (i, &compact) in ptrs.iter().enumerate() {
+                assert_eq!(unsafe { page_alloc.expand(compact).read() }, i as u64);
+            }
+        });
+    });
+    for compact in ptrs {
+        let ptr = page_alloc.expand(compact);
+        unsafe { alloc.deallocate(ptr.cast(), Layout::new::<u64>()) };
+    }
+
+    let x = 1u64;
+    assert!(page_alloc.compact(NonNull::from(&x)).is_none());
+    assert!(CompactPtr::new(page_alloc.base_addr(), NonNull::from(&x)).is_none());
+}
+
+/// Runs f in a forked child process and returns true if the child was killed by SIGSEGV.
+#[cfg(target_os = "linux")]
+fn crashes_in_child(f: impl FnOnce()) -> bool {
+    unsafe {
+        match libc::fork() {
+            -1 => panic!("fork failed"),
+            0 => {
+                f();
+                libc::_exit(0);
+            }
+            pid => {
+                let mut status = 0;
+                assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+                libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSEGV
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_mapping_name() {
+    let mut config = dynamic_page_alloc::Config::new();
+    config.name(c"s_alloc:test");
+    let page_alloc = DynamicPageAlloc::new(config);
+    let page = page_alloc.alloc_page(1 << 21).unwrap();
+    let name = mapping_name(page.cast::<u8>().as_ptr());
+    if name.is_none() {
+        // kernel doesn't support naming anonymous mappings
+        unsafe { page_alloc.dealloc_page(page) };
+        return;
+    }
+    assert_eq!(name.as_deref(), Some("[anon:s_alloc:test]"));
+    let page = unsafe { page_alloc.grow_page(page, 1 << 23) }.unwrap();
+    assert_eq!(
+        mapping_name(unsafe { page.cast::<u8>().as_ptr().add(page.len() - 1) }).as_deref(),
+        Some("[anon:s_alloc:test]")
+    );
+    unsafe { page_alloc.dealloc_page(page) };
+
+    // allocators built on top of the page allocator get named memory
+    let alloc = LocalAlloc::new(local_alloc::Config::new(&page_alloc));
+    let mut v = Vec::<u8, _>::with_capacity_in(1 << 12, &alloc);
+    v.push(1);
+    assert_eq!(
+        mapping_name(v.as_ptr()).as_deref(),
+        Some("[anon:s_alloc:test]")
+    );
+    let mut config = bump_alloc::Config::new(&alloc);
+    config.min_alloc_size(1 << 16);
+    let alloc = BumpAlloc::new(config);
+    let mut v = Vec::<u8, _>::with_capacity_in(1 << 12, &alloc);
+    v.push(1);
+    assert_eq!(
+        mapping_name(v.as_ptr()).as_deref(),
+        Some("[anon:s_alloc:test]")
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_dynamic_page_alloc_attributes() {
+    let mut config = dynamic_page_alloc::Config::new();
+    config.attributes(PageAttributes {
+        dont_dump: true,
+        wipe_on_fork: true,
+        ..Default::default()
+    });
+    let page_alloc = DynamicPageAlloc::new(config);
+    let page = page_alloc.alloc_page(1 << 21).unwrap();
+    let ptr = page.cast::<u8>().as_ptr();
+    unsafe { ptr.write(1) };
+    let flags = mapping_vm_flags(ptr);
+    for flag in ["dd", "wf"] {
+        assert!(flags.iter().any(|x| x == flag), "{flag} in {flags:?}");
+    }
+    // memory is wiped in the child, so the child doesn't crash
+    assert!(!crashes_in_child(|| unsafe {
+        if ptr.read() != 0 {
+            libc::raise(libc::SIGSEGV);
+        }
+    }));
+    unsafe { page_alloc.dealloc_page(page) };
+
+    let mut config = dynamic_page_alloc::Config::new();
+    config.attributes(PageAttributes {
+        lock: true,
+        ..Default::default()
+    });
+    let page_alloc = DynamicPageAlloc::new(config);
+    // locking fails if the pages don't fit into RLIMIT_MEMLOCK and the process can't go above it
+    let assert_lock_limit = |page_alloc: &DynamicPageAlloc| {
+        let errno = page_alloc.last_error().unwrap().raw_os_error();
+        assert!(
+            matches!(errno, Some(libc::EPERM | libc::ENOMEM)),
+            "{errno:?}"
+        );
+    };
+    match page_alloc.alloc_page(1 << 21) {
+        Ok(page) => {
+            let flags = mapping_vm_flags(page.cast::<u8>().as_ptr());
+            assert!(flags.iter().any(|x| x == "lo"), "lo in {flags:?}");
+            match unsafe { page_alloc.grow_page(page, 1 << 22) } {
+                Ok(page) => {
+                    let flags = mapping_vm_flags(page.cast::<u8>().as_ptr());
+                    assert!(flags.iter().any(|x| x == "lo"), "lo in {flags:?}");
+                    unsafe { page_alloc.dealloc_page(page) };
+                }
+                Err(_) => {
+                    assert_lock_limit(&page_alloc);
+                    unsafe { page_alloc.dealloc_page(page) };
+                }
+            }
+        }
+        Err(_) => assert_lock_limit(&page_alloc),
+    }
+
+    let mut config = dynamic_page_alloc::Config::new();
+    config.attributes(PageAttributes {
+        dont_fork: true,
+        ..Default::default()
+    });
+    let page_alloc = DynamicPageAlloc::new(config);
+    let page = page_alloc.alloc_page(1 << 21).unwrap();
+    let ptr = page.cast::<u8>().as_ptr();
+    unsafe { ptr.write(1) };
+    assert!(crashes_in_child(|| unsafe { ptr.write(1) }));
+    unsafe { page_alloc.dealloc_page(page) };
+    test_allocator_all(LocalAlloc::new(local_alloc::Config::new(&page_alloc)));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_residency() {
+    let mut config = dynamic_page_alloc::Config::new();
+    config
+        .huge_pages(HugePagePolicy::None)
+        .commit(CommitStrategy::Lazy);
+    let page_alloc = DynamicPageAlloc::new(config);
+
+    let page = page_alloc.alloc_page(1 << 22).unwrap();
+    let residency = page_alloc.residency().unwrap();
+    assert_eq!(residency.len(), 1);
+    assert_eq!(residency[0].0, page);
+    assert_eq!(residency[0].1.size, 1 << 22);
+    assert_eq!(residency[0].1.resident, 0);
+    unsafe { page.cast::<u8>().as_ptr().write(1) };
+    assert_eq!(page_alloc.residency().unwrap()[0].1.resident, 1 << 12);
+    assert_eq!(page_alloc.residency().unwrap()[0].1.huge_pages, Some(0));
+    let page = unsafe { page_alloc.grow_page(page, 1 << 23) }.unwrap();
+    assert_eq!(page_alloc.residency().unwrap()[0].0, page);
+    unsafe { page_alloc.dealloc_page(page) };
+    assert!(page_alloc.residency().unwrap().is_empty());
+
+    let mut config = local_alloc::Config::new(&page_alloc);
+    config.min_page_size(1 << 22);
+    let alloc = LocalAlloc::new(config);
+    let mut v = Vec::<u8, _>::with_capacity_in(1 << 20, &alloc);
+    v.resize(1 << 20, 1);
+    let residency = alloc.residency().unwrap();
+    assert_eq!(residency.size, 1 << 22);
+    assert_eq!(residency.resident, 1 << 20);
+    drop(v);
+
+    let mut config = bump_alloc::Config::new(&alloc);
+    config.min_alloc_size(1 << 16);
+    let alloc = BumpAlloc::new(config);
+    let mut v = Vec::<u8, _>::with_capacity_in(1 << 12, &alloc);
+    v.resize(1 << 12, 1);
+    let residency = alloc.residency().unwrap();
+    assert_eq!(residency.size, 1 << 16);
+    assert!(residency.resident >= 1 << 12);
+
+    let page_alloc = DynamicPageAlloc::default();
+    let page = page_alloc.alloc_page(1 << 22).unwrap();
+    let residency = page_alloc.residency().unwrap()[0].1;
+    assert_eq!(residency.resident, 1 << 22);
+    assert!(residency.huge_pages.is_some());
+    unsafe { page_alloc.dealloc_page(page) };
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_prefault() {
+    let mut config = dynamic_page_alloc::Config::new();
+    config
+        .huge_pages(HugePagePolicy::None)
+        .commit(CommitStrategy::Background);
+    let page_alloc = DynamicPageAlloc::new(config);
+    let page = page_alloc.alloc_page(1 << 23).unwrap();
+    let start = std::time::Instant::now();
+    while page_alloc.residency().unwrap()[0].1.resident < 1 << 23 {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    unsafe { page_alloc.dealloc_page(page) };
+    test_allocator_all(LocalAlloc::new(local_alloc::Config::new(&page_alloc)));
+
+    // pages are unmapped and moved while they are being faulted in
+    for _ in 0..100 {
+        let page = page_alloc.alloc_page(1 << 23).unwrap();
+        let page = unsafe { page_alloc.grow_page(page, 1 << 24) }.unwrap();
+        let page = unsafe { page_alloc.shrink_page(page, 1 << 22) }.unwrap();
+        unsafe { page_alloc.dealloc_page(page) };
+    }
+    let page = page_alloc.alloc_page(1 << 23).unwrap();
+    let start = std::time::Instant::now();
+    while page_alloc.residency().unwrap()[0].1.resident < 1 << 23 {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    unsafe { page_alloc.dealloc_page(page) };
+
+    let mut config = dynamic_page_alloc::Config::new();
+    config
+        .huge_pages(HugePagePolicy::None)
+        .commit(CommitStrategy::Lazy);
+    let page_alloc = DynamicPageAlloc::new(config);
+    let mut config = local_alloc::Config::new(&page_alloc);
+    config.min_page_size(1 << 22).decommit_threshold(1 << 12);
+    let alloc = LocalAlloc::new(config);
+    alloc.prefault(1 << 20).unwrap();
+    let residency = alloc.residency().unwrap();
+    assert_eq!(residency.size, 1 << 22);
+    assert_eq!(residency.resident, 1 << 20);
+
+    // decommitted memory is faulted in again
+    let mut v = Vec::<u8, _>::with_capacity_in(1 << 21, &alloc);
+    v.resize(1 << 21, 1);
+    drop(v);
+    assert_eq!(alloc.residency().unwrap().resident, 0);
+    alloc.prefault(1 << 22).unwrap();
+    assert_eq!(alloc.residency().unwrap().resident, 1 << 22);
+
+    // a new page is allocated if there isn't enough free memory
+    alloc.prefault(1 << 23).unwrap();
+    let residency = alloc.residency().unwrap();
+    assert!(residency.size >= (1 << 23));
+    assert!(residency.resident >= (1 << 23));
+
+    let alloc = LocalAlloc::new(local_alloc::Config::new(&std::alloc::Global));
+    alloc.prefault(1 << 16).unwrap();
+    test_allocator_all(&alloc);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_allocate_zeroed() {
+    let mut config = dynamic_page_alloc::Config::new();
+    config
+        .huge_pages(HugePagePolicy::None)
+        .commit(CommitStrategy::Lazy);
+    let page_alloc = DynamicPageAlloc::new(config);
+    assert!(page_alloc.zeroes_pages());
+
+    let mut config = local_alloc::Config::new(&page_alloc);
+    config.min_page_size(1 << 23);
+    let alloc = LocalAlloc::new(config);
+    let layout = Layout::from_size_align(1 << 20, 1 << 12).unwrap();
+    let is_zeroed = |ptr: NonNull<[u8]>| unsafe { ptr.as_ref().iter().all(|&x| x == 0) };
+
+    // memory of a fresh page isn't touched
+    let ptr = alloc.allocate_zeroed(layout).unwrap();
+    assert_eq!(alloc.residency().unwrap().resident, 0);
+    assert!(is_zeroed(ptr));
+    unsafe { ptr.cast::<u8>().as_ptr().write_bytes(1, 1 << 20) };
+    unsafe { alloc.deallocate(ptr.cast(), layout) };
+
+    // used memory is zeroed again
+    let ptr = alloc.allocate_zeroed(layout).unwrap();
+    assert!(is_zeroed(ptr));
+    unsafe { ptr.cast::<u8>().as_ptr().write_bytes(1, 1 << 20) };
+    let ptr2 = alloc.allocate_zeroed(layout).unwrap();
+    assert!(is_zeroed(ptr2));
+    unsafe { alloc.deallocate(ptr.cast(), layout) };
+    unsafe { alloc.deallocate(ptr2.cast(), layout) };
+
+    // zeroed chunks of a BumpAlloc are taken from the pristine part of the page
+    let resident = alloc.residency().unwrap().resident;
+    let mut config = bump_alloc::Config::new(&alloc);
+    config.min_alloc_size(1 << 21);
+    let bump = BumpAlloc::new(config);
+    let ptrs = (0..100)
+        .map(|_| bump.allocate_zeroed(Layout::new::<[u64; 64]>()).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(alloc.residency().unwrap().resident, resident);
+    assert!(ptrs.into_iter().all(is_zeroed));
+    let mut v = Vec::<u8, _>::with_capacity_in(1 << 12, &bump);
+    v.resize(1 << 12, 1);
+    let ptr = bump.allocate_zeroed(Layout::new::<[u64; 64]>()).unwrap();
+    assert!(is_zeroed(ptr));
+
+    let alloc = LocalAlloc::new(local_alloc::Config::new(&std::alloc::Global));
+    let ptr = alloc.allocate(layout).unwrap();
+    unsafe { ptr.cast::<u8>().as_ptr().write_bytes(1, 1 << 20) };
+    unsafe { alloc.deallocate(ptr.cast(), layout) };
+    let ptr = alloc.allocate_zeroed(layout).unwrap();
+    assert!(is_zeroed(ptr));
+    unsafe { alloc.deallocate(ptr.cast(), layout) };
+}
+
+/// Returns the VmFlags of the mapping that contains ptr from /proc/self/smaps.
+#[cfg(target_os = "linux")]
+fn mapping_vm_flags(ptr: *const u8) -> Vec<String> {
+    let addr = ptr as usize;
+    let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+    let mut in_mapping = false;
+    for line in smaps.lines() {
+        if let Some((range, _)) = line.split_once(' ') {
+            if let Some((start, end)) = range.split_once('-') {
+                if let (Ok(start), Ok(end)) = (
+                    usize::from_str_radix(start, 16),
+                    usize::from_str_radix(end, 16),
+                ) {
+                    in_mapping = addr >= start && addr < end;
+                    continue;
+                }
+            }
+        }
+        if let Some(flags) = line.strip_prefix("VmFlags:") {
+            if in_mapping {
+                return flags.split_whitespace().map(|x| x.to_owned()).collect();
+            }
+        }
+    }
+    panic!("mapping not found");
+}
+
+/// Returns the name of the mapping that contains ptr from /proc/self/maps.
+#[cfg(target_os = "linux")]
+fn mapping_name(ptr: *const u8) -> Option<String> {
+    let addr = ptr as usize;
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    for line in maps.lines() {
+        let mut fields = line.split_whitespace();
+        let (start, end) = fields.next().unwrap().split_once('-').unwrap();
+        let start = usize::from_str_radix(start, 16).unwrap();
+        let end = usize::from_str_radix(end, 16).unwrap();
+        if addr >= start && addr < end {
+            return fields.nth(4).map(|name| name.to_owned());
+        }
+    }
+    None
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_dynamic_page_alloc_error() {
+    let page_alloc = DynamicPageAlloc::default();
+    assert!(page_alloc.last_error().is_none());
+    assert!(page_alloc.alloc_page(1 << 62).is_err());
+    assert_eq!(
+        page_alloc.last_error().unwrap().raw_os_error(),
+        Some(libc::ENOMEM)
+    );
+}
+
+#[test]
+fn test_caching_page_alloc() {
+    let mut config = caching_page_alloc::Config::new(std::alloc::Global);
+    config.max_cached_bytes(1 << 20);
+    let page_alloc = CachingPageAlloc::new(config);
+
+    let page = page_alloc.alloc_page(1 << 16).unwrap();
+    unsafe { page_alloc.dealloc_page(page) };
+    assert_eq!(page_alloc.cached_bytes(), 1 << 16);
+    let cached_page = page_alloc.alloc_page(1 << 15).unwrap();
+    assert_eq!(cached_page, page);
+    assert_eq!(page_alloc.cached_bytes(), 0);
+    unsafe { page_alloc.dealloc_page(cached_page) };
+
+    // doesn't fit into the cache
+    let big_page = page_alloc.alloc_page(1 << 21).unwrap();
+    unsafe { page_alloc.dealloc_page(big_page) };
+    assert_eq!(page_alloc.cached_bytes(), 1 << 16);
+
+    page_alloc.trim();
+    assert_eq!(page_alloc.cached_bytes(), 0);
+
+    for _ in 0..3 {
+        test_local_alloc_on(&page_alloc, 1 << 16);
+        assert!(page_alloc.cached_bytes() > 0);
+    }
+}
+
+#[test]
+fn test_budget_page_alloc() {
+    let page_alloc =
+        BudgetPageAlloc::new(budget_page_alloc::Config::new(std::alloc::Global, 1 << 20));
+
+    let page = page_alloc.alloc_page(1 << 19).unwrap();
+    assert_eq!(page_alloc.usage(), 1 << 19);
+    assert!(page_alloc.alloc_page(3 << 18).is_err());
+    let page = unsafe { page_alloc.grow_page(page, 1 << 20) }.unwrap();
+    assert_eq!(page_alloc.usage(), 1 << 20);
+    let page = unsafe { page_alloc.shrink_page(page, 1 << 18) }.unwrap();
+    assert_eq!(page_alloc.usage(), 1 << 18);
+    unsafe { page_alloc.dealloc_page(page) };
+    assert_eq!(page_alloc.usage(), 0);
+    assert_eq!(page_alloc.peak_usage(), 1 << 20);
+
+    // Global doesn't give decommitted memory back, so it still counts
+    let page = page_alloc.alloc_page(1 << 20).unwrap();
+    let half = NonNull::slice_from_raw_parts(page.cast::<u8>(), 1 << 19);
+    unsafe { page_alloc.decommit(half) };
+    assert_eq!(page_alloc.usage(), 1 << 20);
+    unsafe { page_alloc.recommit(half) };
+    assert_eq!(page_alloc.usage(), 1 << 20);
+    unsafe { page_alloc.dealloc_page(page) };
+    assert_eq!(page_alloc.usage(), 0);
+
+    let page_alloc =
+        BudgetPageAlloc::new(budget_page_alloc::Config::new(std::alloc::Global, 1 << 24));
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                test_local_alloc_on(&page_alloc, 1 << 16);
+            });
+        }
+    });
+    assert_eq!(page_alloc.usage(), 0);
+    assert!(page_alloc.peak_usage() > 0);
+    assert!(page_alloc.peak_usage() <= page_alloc.limit());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_budget_page_alloc_decommit() {
+    let page_alloc = BudgetPageAlloc::new(budget_page_alloc::Config::new(
+        DynamicPageAlloc::default(),
+        1 << 22,
+    ));
+
+    let page = page_alloc.alloc_page(1 << 22).unwrap();
+    assert_eq!(page_alloc.usage(), 1 << 22);
+    let ptr = page.cast::<u8>();
+    let range = |start: usize, end: usize| {
+        NonNull::slice_from_raw_parts(unsafe { ptr.add(start) }, end - start)
+    };
+    unsafe { page_alloc.decommit(range(0, 1 << 21)) };
+    assert_eq!(page_alloc.usage(), 1 << 21);
+    unsafe { page_alloc.decommit(range(1 << 20, 3 << 20)) };
+    assert_eq!(page_alloc.usage(), 1 << 20);
+    let other_page = page_alloc.alloc_page(1 << 21).unwrap();
+    unsafe { page_alloc.recommit(range(1 << 19, 1 << 20)) };
+    unsafe { page_alloc.recommit(range(1 << 19, 1 << 20)) };
+    assert_eq!(page_alloc.usage(), (3 << 20) + (1 << 19));
+
+    // recommitting goes over the limit, which blocks allocations until usage is below it
+    unsafe { page_alloc.recommit(range(0, 3 << 20)) };
+    assert_eq!(page_alloc.usage(), 6 << 20);
+    assert_eq!(page_alloc.peak_usage(), 6 << 20);
+    assert!(page_alloc.alloc_page(1 << 12).is_err());
+    unsafe { page_alloc.dealloc_page(other_page) };
+    assert_eq!(page_alloc.usage(), 1 << 22);
+
+    unsafe { page_alloc.decommit(range(0, 1 << 20)) };
+    assert_eq!(page_alloc.usage(), 3 << 20);
+    let page = unsafe { page_alloc.shrink_page(page, 1 << 21) }.unwrap();
+    assert_eq!(page_alloc.usage(), 1 << 21);
+    let ptr = page.cast::<u8>();
+    unsafe { page_alloc.decommit(NonNull::slice_from_raw_parts(ptr, 1 << 20)) };
+    assert_eq!(page_alloc.usage(), 1 << 20);
+    unsafe { page_alloc.dealloc_page(page) };
+    assert_eq!(page_alloc.usage(), 0);
+}
+
+#[test]
+fn test_local_alloc_on_bump_alloc() {
+    let mut config = bump_alloc::Config::new(std::alloc::Global);
+    config.min_alloc_size(1 << 22);
+    let bump = BumpAlloc::new(config);
+    let page_alloc = AllocatorPageAlloc::new(&bump);
+    // pages are rounded to 4KB and carved out of the same chunk of the BumpAlloc
+    let page = page_alloc.alloc_page(1).unwrap();
+    let next_page = page_alloc.alloc_page(1 << 12).unwrap();
+    assert_eq!(page.len(), 1 << 12);
+    assert_eq!(page.cast::<u8>().align_offset(1 << 12), 0);
+    assert_eq!(next_page.cast::<u8>(), unsafe {
+        page.cast::<u8>().add(1 << 12)
+    });
+    unsafe {
+        page_alloc.dealloc_page(next_page);
+        page_alloc.dealloc_page(page);
+    }
+    test_local_alloc_on(&page_alloc, 1 << 20);
+    test_page_resize(&page_alloc);
+
+    let page_alloc = AllocatorPageAlloc::new(ValidatingAllocator::new(std::alloc::Global));
+    let alloc = test_local_alloc_on(&page_alloc, 1 << 16);
+    let mut v = Vec::<u64, _>::new_in(&alloc);
+    for i in 0..1 << 16 {
+        v.push(i);
+    }
+    assert!(v.iter().enumerate().all(|(i, &x)| x == i as u64));
+    drop(v);
+    drop(alloc);
+    test_page_resize(&page_alloc);
+}
+
+#[test]
+fn test_local_alloc_fragmentation() {
+    let page_alloc =
+        BudgetPageAlloc::new(budget_page_alloc::Config::new(std::alloc::Global, 1 << 30));
+    let mut config = local_alloc::Config::new(&page_alloc);
+    config.min_page_size(1 << 16).free_after(0);
+    let alloc = ValidatingAllocator::new(LocalAlloc::new(config));
+
+    let mut state = 69u64;
+    let mut rand = move || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (state >> 33) as usize
+    };
+
+    let check = |ptr: NonNull<[u8]>, tag: u8| unsafe {
+        assert!(
+            std::slice::from_raw_parts(ptr.cast::<u8>().as_ptr(), ptr.len())
+                .iter()
+                .all(|&x| x == tag)
+        );
+    };
+
+    let mut allocs = Vec::<(NonNull<[u8]>, Layout, u8)>::new();
+    for round in 0..8 {
+        for _ in 0..500 {
+            let layout = Layout::from_size_align(1 + rand() % 3000, 1 << (rand() % 10)).unwrap();
+            let ptr = alloc.allocate(layout).unwrap();
+            let tag = rand() as u8;
+            unsafe { ptr.cast::<u8>().as_ptr().write_bytes(tag, layout.size()) };
+            allocs.push((ptr, layout, tag));
+        }
+
+        // free part of the allocations to leave holes of all sizes behind
+        let mut idx = round % 2;
+        while idx < allocs.len() {
+            let (ptr, layout, tag) = allocs.swap_remove(idx);
+            check(ptr, tag);
+            unsafe { alloc.deallocate(ptr.cast::<u8>(), layout) };
+            idx += 2;
+        }
+    }
+
+    for (ptr, layout, tag) in allocs {
+        check(ptr, tag);
+        unsafe { alloc.deallocate(ptr.cast::<u8>(), layout) };
+    }
+    assert_eq!(page_alloc.usage(), 0);
+}
+
+#[test]
+fn test_local_alloc_placement() {
+    let mut config = local_alloc::Config::new(&std::alloc::Global);
+    config.min_page_size(1 << 16);
+    let alloc = LocalAlloc::new(config);
+    let layout = |size| Layout::from_size_align(size, 8).unwrap();
+
+    let big = alloc.allocate(layout(8192)).unwrap();
+    let spacer = alloc.allocate(layout(16)).unwrap();
+    let small = alloc.allocate(layout(4096)).unwrap();
+    let spacer2 = alloc.allocate(layout(16)).unwrap();
+    unsafe {
+        alloc.deallocate(big.cast::<u8>(), layout(8192));
+        alloc.deallocate(small.cast::<u8>(), layout(4096));
+    }
+
+    // the free range of the same size class is used, even though there are bigger ones at lower addresses
+    let ptr = alloc.allocate(layout(4096)).unwrap();
+    assert_eq!(ptr.cast::<u8>(), small.cast::<u8>());
+    // the lowest range of the smallest fitting class is used next
+    let ptr2 = alloc.allocate(layout(4096)).unwrap();
+    assert_eq!(ptr2.cast::<u8>(), big.cast::<u8>());
+
+    unsafe {
+        for (ptr, size) in [(ptr, 4096), (ptr2, 4096), (spacer, 16), (spacer2, 16)] {
+            alloc.deallocate(ptr.cast::<u8>(), layout(size));
+        }
+    }
+
+    // the fitting range isn't the first one of its size class
+    let sizes = [104, 6000, 2088, 4096, 16, (1 << 16) - 12304];
+    let ptrs = sizes.map(|size| alloc.allocate(layout(size)).unwrap());
+    unsafe {
+        alloc.deallocate(ptrs[1].cast::<u8>(), layout(6000));
+        alloc.deallocate(ptrs[3].cast::<u8>(), layout(4096));
+    }
+    let aligned_layout = Layout::from_size_align(4096, 4096).unwrap();
+    let ptr = alloc.allocate(aligned_layout).unwrap();
+    assert_eq!(ptr.cast::<u8>(), ptrs[3].cast::<u8>());
+    unsafe {
+        alloc.deallocate(ptr.cast::<u8>(), aligned_layout);
+        for i in [0, 2, 4, 5] {
+            alloc.deallocate(ptrs[i].cast::<u8>(), layout(sizes[i]));
+        }
+    }
+}
+
+#[test]
+fn test_local_alloc_many_allocations() {
+    let page_alloc =
+        BudgetPageAlloc::new(budget_page_alloc::Config::new(std::alloc::Global, 1 << 30));
+    let mut config = local_alloc::Config::new(&page_alloc);
+    config.min_page_size(1 << 16).free_after(0);
+    let alloc = LocalAlloc::new(config);
+    let layout = Layout::from_size_align(1000, 8).unwrap();
+
+    // the rest of the page is free, so the allocation grows in place
+    let ptr = alloc.allocate(layout).unwrap();
+    let grown_layout = Layout::from_size_align(3000, 8).unwrap();
+    let grown = unsafe { alloc.grow(ptr.cast::<u8>(), layout, grown_layout) }.unwrap();
+    assert_eq!(grown.cast::<u8>(), ptr.cast::<u8>());
+    unsafe { alloc.deallocate(grown.cast::<u8>(), grown_layout) };
+    assert_eq!(page_alloc.usage(), 0);
+
+    let ptrs = (0..1 << 14)
+        .map(|_| alloc.allocate(layout).unwrap())
+        .collect::<Vec<_>>();
+    assert!(page_alloc.usage() >= 1000 << 14);
+    // free every other allocation first so freed ranges have to be merged with both neighbours
+    for ptr in ptrs.iter().step_by(2) {
+        unsafe { alloc.deallocate(ptr.cast::<u8>(), layout) };
+    }
+    for ptr in ptrs.iter().skip(1).step_by(2).rev() {
+        unsafe { alloc.deallocate(ptr.cast::<u8>(), layout) };
+    }
+    assert_eq!(page_alloc.usage(), 0);
+}
+
+#[test]
+fn test_fallback_page_alloc() {
+    let mut buf = vec![0u8; (1 << 20) + (1 << 12)].into_boxed_slice();
+    let page_alloc = FallbackPageAlloc::new(fallback_page_alloc::Config::new(
+        FixedBufferPageAlloc::new(&mut buf),
+        DynamicPageAlloc::default(),
+    ));
+    let primary_bytes = page_alloc.primary().free_bytes();
+
+    let mut pages = (0..4)
+        .map(|_| page_alloc.alloc_page(1 << 18).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(page_alloc.fallback_count(), 0);
+    pages.push(page_alloc.alloc_page(1 << 18).unwrap());
+    assert_eq!(page_alloc.fallback_count(), 1);
+
+    // growing a page that doesn't fit into the buffer moves it to the fallback
+    unsafe {
+        let page = pages.remove(0);
+        page.cast::<u8>().as_ptr().write(1);
+        let page = page_alloc.grow_page(page, 1 << 21).unwrap();
+        assert_eq!(page.cast::<u8>().as_ptr().read(), 1);
+        pages.push(page);
+    }
+    assert_eq!(page_alloc.fallback_count(), 2);
+
+    for page in pages {
+        unsafe { page_alloc.dealloc_page(page) };
+    }
+    assert_eq!(page_alloc.primary().free_bytes(), primary_bytes);
+
+    let alloc = test_local_alloc_on(&page_alloc, 1 << 16);
+    test_allocator_large_alignment(&alloc);
+    drop(alloc);
+    assert_eq!(page_alloc.primary().free_bytes(), primary_bytes);
+
+    test_page_resize(&page_alloc);
+    assert_eq!(page_alloc.primary().free_bytes(), primary_bytes);
+}
+
+#[test]
+fn test_owning_local_alloc() {
+    let alloc = LocalAlloc::new(local_alloc::Config::new(DynamicPageAlloc::default()));
+    test_allocator_all(&alloc);
+
+    let page_alloc: Box<dyn PageAlloc> = Box::new(std::alloc::Global);
+    test_allocator_all(LocalAlloc::new(local_alloc::Config::new(page_alloc)));
+
+    // the LocalAlloc keeps its share of the page allocator until it is dropped
+    let page_alloc = std::rc::Rc::new(DynamicPageAlloc::default());
+    let alloc = test_local_alloc_on(page_alloc.clone(), 1 << 21);
+    assert_eq!(std::rc::Rc::strong_count(&page_alloc), 2);
+    let x = Box::new_in(1u64, &alloc);
+    assert_eq!(page_alloc.residency().unwrap().len(), 1);
+    drop(x);
+    drop(alloc);
+    assert_eq!(std::rc::Rc::strong_count(&page_alloc), 1);
+    assert!(page_alloc.residency().unwrap().is_empty());
+    test_allocator_all(LocalAlloc::new(local_alloc::Config::new(page_alloc)));
+
+    let page_alloc = std::sync::Arc::new(BudgetPageAlloc::new(budget_page_alloc::Config::new(
+        DynamicPageAlloc::default(),
+        1 << 30,
+    )));
+    let threads = (0..4)
+        .map(|_| {
+            let page_alloc = page_alloc.clone();
+            std::thread::spawn(move || {
+                thread_local! {
+                    static ALLOC: std::cell::OnceCell<LocalAlloc<std::sync::Arc<BudgetPageAlloc<DynamicPageAlloc>>>> =
+                        const { std::cell::OnceCell::new() };
+                }
+                ALLOC.with(|alloc| {
+                    let alloc = alloc.get_or_init(|| {
+                        let mut config = local_alloc::Config::new(page_alloc);
+                        config.min_page_size(1 << 21);
+                        LocalAlloc::new(config)
+                    });
+                    test_allocator_all(alloc);
+                });
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(page_alloc.usage(), 0);
+    assert!(page_alloc.peak_usage() > 0);
+}
+
+#[test]
+fn test_fixed_buffer_page_alloc() {
+    let mut buf = vec![0u8; 1 << 26].into_boxed_slice();
+    let page_alloc = FixedBufferPageAlloc::new(&mut buf);
+    let total = page_alloc.free_bytes();
+    assert!(total >= (1 << 26) - (1 << 12));
+
+    {
+        let alloc = test_local_alloc_on(&page_alloc, 1 << 16);
+        test_allocator_large_alignment(&alloc);
+
+        let alloc = BumpAlloc::new(bump_alloc::Config::new(&alloc));
+        test_allocator_all(alloc);
+    }
+    assert_eq!(page_alloc.free_bytes(), total);
+
+    test_page_resize(&page_alloc);
+    assert_eq!(page_alloc.free_bytes(), total);
+
+    let page = page_alloc.alloc_page(total).unwrap();
+    assert!(page_alloc.alloc_page(1).is_err());
+    unsafe { page_alloc.dealloc_page(page) };
+    let page = page_alloc.alloc_page(total).unwrap();
+    unsafe { page_alloc.dealloc_page(page) };
+}
+
+fn test_page_resize<P: PageAlloc>(page_alloc: &P) {
+    unsafe {
+        let page = page_alloc.alloc_page(1 << 21).unwrap();
+        let len = page.len();
+        let ptr = page.cast::<u8>().as_ptr();
+        for i in 0..len {
+            *ptr.add(i) = i as u8;
+        }
+
+        let page = page_alloc.grow_page(page, len * 4).unwrap();
+        assert!(page.len() >= len * 4);
+        let ptr = page.cast::<u8>().as_ptr();
+        for i in 0..len {
+            assert_eq!(*ptr.add(i), i as u8);
+        }
+        ptr.add(page.len() - 1).write(1);
+
+        let page = page_alloc.shrink_page(page, len / 2).unwrap();
+        assert!(page.len() >= len / 2);
+        let ptr = page.cast::<u8>().as_ptr();
+        for i in 0..len / 2 {
+            assert_eq!(*ptr.add(i), i as u8);
+        }
+
+        page_alloc.dealloc_page(page);
+    }
+}
+
 fn test_allocator<Alloc: Allocator>(alloc: Alloc) {
     let alloc = ValidatingAllocator::new(alloc);
     let layout = Layout::new::<i32>().repeat(100).unwrap().0;
@@ -82,13 +1377,38 @@ fn test_allocator_aligned<Alloc: Allocator>(alloc: Alloc) {
     }
 }
 
-// fn test_allocator_large_alignment<Alloc: Allocator>(alloc: Alloc) {}
+// Only some allocators support alignments above 4KB so this isn't a part of test_allocator_all
+fn test_allocator_large_alignment<Alloc: Allocator>(alloc: Alloc) {
+    let alloc = ValidatingAllocator::new(alloc);
+    let mut aligns = Vec::<(NonNull<[u8]>, Layout), &ValidatingAllocator<Alloc>>::new_in(&alloc);
+    for pow in 12..23 {
+        let alignment = 1 << pow;
+
+        let layout = Layout::from_size_align(69, alignment).unwrap();
+        let ptr = alloc.allocate(layout).unwrap();
+
+        aligns.push((ptr, layout));
+    }
+
+    for (ptr, layout) in aligns {
+        unsafe { alloc.deallocate(ptr.cast::<u8>(), layout) };
+    }
+}
 
 // fn test_allocator_aligned_shrink<Alloc: Allocator>(alloc: Alloc) {}
 
+/// Runs test_allocator_all on a LocalAlloc that allocates pages of at least min_page_size from page_alloc.
+/// The allocator is returned so more checks can be done on it.
+fn test_local_alloc_on<P: PageAlloc>(page_alloc: P, min_page_size: usize) -> LocalAlloc<P> {
+    let mut config = local_alloc::Config::new(page_alloc);
+    config.min_page_size(min_page_size);
+    let alloc = LocalAlloc::new(config);
+    test_allocator_all(&alloc);
+    alloc
+}
+
 fn test_allocator_all<Alloc: Allocator>(alloc: Alloc) {
     test_allocator(&alloc);
     test_allocator_aligned(&alloc);
-    // test_allocator_large_alignment(&alloc);
     // test_allocator_aligned_shrink(&alloc);
 }
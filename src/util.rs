@@ -6,6 +6,10 @@ pub fn align_up(ptr: usize, align: usize) -> usize {
     (ptr + align - 1) & !(align - 1)
 }
 
+pub fn align_down(ptr: usize, align: usize) -> usize {
+    ptr & !(align - 1)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;